@@ -130,6 +130,14 @@ impl Cbmd {
         &self.title
     }
 
+    pub fn get_intro_words(&self) -> &str {
+        &self.intro_words
+    }
+
+    pub fn get_path(&self) -> &str {
+        &self.path
+    }
+
     pub fn serialize(&self) -> Vec<u8> {
         let title_len = self.title.len();
         let words_len = self.intro_words.len();
@@ -1,20 +1,46 @@
 use std::fmt::Debug;
 use std::{collections::HashMap, time::Instant, net::IpAddr};
-use std::sync::RwLock;
-use crate::types::{Response, Request};
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use crate::types::{CorsPolicy, Response, Request, HTTPType};
 
 type InnerApi = Box<dyn Fn(Request) -> Response + Send + Sync + 'static>;
 
+// the order methods are listed in the Allow header
+const METHOD_ORDER: [HTTPType; 7] = [
+    HTTPType::Get,
+    HTTPType::Head,
+    HTTPType::Post,
+    HTTPType::Put,
+    HTTPType::Patch,
+    HTTPType::Delete,
+    HTTPType::Options,
+];
 
 pub struct Api {
-    inner: InnerApi,
+    segments: Vec<Segment>,
+    // handles every method itself, from register_api
+    any_method: Option<InnerApi>,
+    // from register_route, anything not in here gets a 405
+    methods: HashMap<HTTPType, InnerApi>,
     limit_count: usize,
     seconds_till_refresh: u32,
 }
 
+// one part of a route pattern between the slashes
+#[derive(Debug, PartialEq, Eq)]
+enum Segment {
+    Static(String),
+    // ':slug', matches exactly one segment
+    Param(String),
+    // '*' or '*rest', matches everything left (even nothing)
+    Wildcard(String),
+}
+
 impl Debug for Api {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Api")
+            .field("segments", &self.segments)
+            .field("methods", &self.methods.keys().collect::<Vec<&HTTPType>>())
             .field("limit_count", &self.limit_count)
             .field("seconds_till_refresh", &self.seconds_till_refresh)
             .finish()
@@ -22,17 +48,146 @@ impl Debug for Api {
 }
 
 impl Api {
-    pub fn run(&self, req: Request) -> Response {
-        (self.inner)(req)
+    fn new(pattern: &str, limit: usize, refresh_timer: u32) -> Self {
+        Self {
+            segments: parse_pattern(pattern),
+            any_method: None,
+            methods: HashMap::new(),
+            limit_count: limit,
+            seconds_till_refresh: refresh_timer,
+        }
+    }
+
+    // picks the handler for the requests method, HEAD gets the GET handler
+    // (the body gets left off when its sent) and OPTIONS/405 are answered here
+    pub fn run(&self, req: Request, cors: &CorsPolicy) -> Response {
+        if let Some(inner) = &self.any_method {
+            return inner(req);
+        }
+
+        let kind = req.get_kind();
+        let handler = match kind {
+            HTTPType::Head => self.methods.get(&HTTPType::Head).or_else(|| self.methods.get(&HTTPType::Get)),
+            _ => self.methods.get(&kind),
+        };
+
+        match (handler, kind) {
+            (Some(inner), _) => inner(req),
+            (None, HTTPType::Options) => Response::new_preflight(&req, &self.allowed_methods(), cors),
+            (None, _) => Response::new_405_error(&self.allowed_methods()),
+        }
+    }
+
+    // 'GET, HEAD, OPTIONS' for an api that only has a GET handler
+    pub fn allowed_methods(&self) -> String {
+        METHOD_ORDER.iter()
+            .filter(|method| match method {
+                HTTPType::Head => self.methods.contains_key(&HTTPType::Head) || self.methods.contains_key(&HTTPType::Get),
+                HTTPType::Options => true,
+                _ => self.methods.contains_key(method),
+            })
+            .map(|method| method.to_string())
+            .collect::<Vec<String>>()
+            .join(", ")
     }
 
     fn get_limit_and_refresh(&self) -> (usize, u32) {
         (self.limit_count, self.seconds_till_refresh)
     }
+
+    // the params in the path if it matches this apis pattern
+    fn matches(&self, path: &str) -> Option<HashMap<String, String>> {
+        let mut params = HashMap::new();
+        let mut parts = path.trim_start_matches('/').split('/');
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Wildcard(name) => {
+                    let rest = parts.collect::<Vec<&str>>().join("/");
+                    params.insert(name.clone(), rest);
+                    return Some(params);
+                },
+                Segment::Static(name) => {
+                    if parts.next()? != name {
+                        return None;
+                    }
+                },
+                Segment::Param(name) => {
+                    let part = parts.next()?;
+                    if part.is_empty() {
+                        return None;
+                    }
+                    params.insert(name.clone(), part.to_owned());
+                },
+            }
+        }
+
+        match parts.next() {
+            None => Some(params),
+            Some(_) => None,
+        }
+    }
+
+    // when more than one pattern matches the most specific one wins, a plain
+    // segment beats a :param which beats a wildcard, earliest segment first
+    fn specificity(&self) -> Vec<u8> {
+        self.segments.iter()
+            .map(|segment| match segment {
+                Segment::Static(_) => 2,
+                Segment::Param(_) => 1,
+                Segment::Wildcard(_) => 0,
+            })
+            .collect()
+    }
+}
+
+// patterns are only ever written in main so a bad one is a bug, this panics
+// instead of letting it quietly match something other than what was meant
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    let segments = pattern.trim_start_matches('/')
+        .split('/')
+        .map(|part| {
+            if let Some(name) = part.strip_prefix(':') {
+                Segment::Param(name.to_owned())
+            } else if let Some(name) = part.strip_prefix('*') {
+                Segment::Wildcard(name.to_owned())
+            } else {
+                Segment::Static(part.to_owned())
+            }
+        })
+        .collect::<Vec<Segment>>();
+
+    // a wildcard takes everything after it so nothing else could ever match
+    let wildcard = segments.iter().position(|segment| matches!(segment, Segment::Wildcard(_)));
+    if let Some(position) = wildcard {
+        if position != segments.len() - 1 {
+            panic!("Api pattern {:?} has segments after its wildcard", pattern);
+        }
+    }
+    segments
+}
+
+// whether two patterns match exactly the same paths, which is when they only
+// differ in what their params and wildcards are called
+fn same_shape(a: &[Segment], b: &[Segment]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|pair| match pair {
+        (Segment::Static(a), Segment::Static(b)) => a == b,
+        (Segment::Param(_), Segment::Param(_)) => true,
+        (Segment::Wildcard(_), Segment::Wildcard(_)) => true,
+        _ => false,
+    })
+}
+
+// what get_api found, pattern is what its rate limits are kept under
+pub struct ApiMatch<'a> {
+    pub pattern: &'a str,
+    pub api: &'a Api,
+    pub params: HashMap<String, String>,
 }
 
 #[derive(Debug)]
 pub struct ApiRegister {
+    // keyed by the pattern they were registered with
     apis: HashMap<String, Api>,
     users: RwLock<HashMap<IpAddr, User>>,
 }
@@ -45,37 +200,102 @@ impl ApiRegister {
         }
     }
 
+    // the api gets every request for the path no matter the method, so it
+    // cant share the path with anything else
     pub fn register_api(&mut self, path: &str, inner_api: InnerApi, limit: usize, refresh_timer: u32) {
-        let api = Api {
-            inner: inner_api,
-            limit_count: limit,
-            seconds_till_refresh: refresh_timer,
-        };
+        self.check_conflicts(path);
+        if self.apis.contains_key(path) {
+            panic!("Api pattern {:?} is already registered", path);
+        }
+        let mut api = Api::new(path, limit, refresh_timer);
+        api.any_method = Some(inner_api);
         self.apis.insert(path.into(), api);
     }
 
-    pub fn get_api(&self, path: &str) -> Option<&Api> {
-        self.apis.get(path)
+    // adds a handler for one method, the pattern can have ':name' segments and
+    // end in a '*' wildcard like '/api/blog/:slug' or '/api/files/*path'
+    // the limits are shared by every method on the same pattern
+    pub fn register_route(&mut self, method: HTTPType, pattern: &str, inner_api: InnerApi, limit: usize, refresh_timer: u32) {
+        self.check_conflicts(pattern);
+        let api = self.apis.entry(pattern.into())
+            .or_insert_with(|| Api::new(pattern, limit, refresh_timer));
+        // either would quietly replace a handler that was meant to be used
+        if api.any_method.is_some() {
+            panic!("Api pattern {:?} already has a handler for every method", pattern);
+        }
+        if api.methods.contains_key(&method) {
+            panic!("Api pattern {:?} already has a {} handler", pattern, method);
+        }
+        api.limit_count = limit;
+        api.seconds_till_refresh = refresh_timer;
+        api.methods.insert(method, inner_api);
+    }
+
+    // '/api/:a' and '/api/:b' would both match every path the other does and
+    // which one got picked would come down to the hashmaps order. Patterns
+    // that only overlap like '/api/:a' and '/api/*rest' are fine, specificity
+    // always picks the same one. It can only tie when every segment is the
+    // same kind, and then differing plain segments mean no path matches both
+    fn check_conflicts(&self, pattern: &str) {
+        let segments = parse_pattern(pattern);
+        let conflict = self.apis.iter()
+            .find(|(other, api)| other.as_str() != pattern && same_shape(&api.segments, &segments));
+        if let Some((other, _)) = conflict {
+            panic!("Api pattern {:?} matches the same paths as {:?}", pattern, other);
+        }
+    }
+
+    pub fn get_api(&self, path: &str) -> Option<ApiMatch<'_>> {
+        self.apis.iter()
+            .filter_map(|(pattern, api)| {
+                let params = api.matches(path)?;
+                Some(ApiMatch {
+                    pattern: pattern.as_str(),
+                    api,
+                    params,
+                })
+            })
+            .max_by(|a, b| a.api.specificity().cmp(&b.api.specificity()))
+    }
+
+    // a panic while one of these was held would poison the lock and make
+    // every request after it panic too, the map is still usable (worst case
+    // a rate limit missed one request) so the poison is ignored
+    fn read_users(&self) -> RwLockReadGuard<'_, HashMap<IpAddr, User>> {
+        self.users.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write_users(&self) -> RwLockWriteGuard<'_, HashMap<IpAddr, User>> {
+        self.users.write().unwrap_or_else(PoisonError::into_inner)
     }
 
     pub fn user_exists(&self, ip: &IpAddr) -> bool {
-        let reader = self.users.read().unwrap();
+        let reader = self.read_users();
         reader.contains_key(ip)
     }
 
+    // the cleaner can remove a user between add_user and these, which
+    // just means they start over with a clean slate
     pub fn check_limit(&self, ip: &IpAddr, api_path: &str) -> bool {
-        let mut writer = self.users.write().unwrap();
-        writer.get_mut(ip).unwrap().check_limit(api_path)
+        let mut writer = self.write_users();
+        match writer.get_mut(ip) {
+            Some(user) => user.check_limit(api_path),
+            None => true,
+        }
     }
 
     pub fn add_request(&self, api_path: &str, user_ip: IpAddr) {
-        let mut writer = self.users.write().unwrap();
-        writer.get_mut(&user_ip).unwrap().add_request(api_path);
+        let mut writer = self.write_users();
+        if let Some(user) = writer.get_mut(&user_ip) {
+            user.add_request(api_path);
+        }
     }
 
     pub fn add_gloabal_request(&self, user_ip: IpAddr) {
-        let mut writer = self.users.write().unwrap();
-        writer.get_mut(&user_ip).unwrap().add_gloabal_request();
+        let mut writer = self.write_users();
+        if let Some(user) = writer.get_mut(&user_ip) {
+            user.add_gloabal_request();
+        }
     }
 
     pub fn add_user(&self, user_ip: IpAddr) {
@@ -89,19 +309,19 @@ impl ApiRegister {
 
         let mut user = User::new();
         user.add_many(limits);
-        let mut inserter = self.users.write().unwrap();
+        let mut inserter = self.write_users();
         inserter.insert(user_ip, user);
     }
 
     pub fn clean_recent_requests(&self) {
-        let reader = self.users.read().unwrap();
+        let reader = self.read_users();
         let keys_to_remove = reader.iter()
             .filter(|(_, user)| user.get_recent_request_count() == 0)
             .map(|(key, _)| *key)
             .collect::<Vec<IpAddr>>();
 
         drop(reader);
-        let mut inserter = self.users.write().unwrap();
+        let mut inserter = self.write_users();
 
         for key in keys_to_remove {
             inserter.remove(&key);
@@ -222,4 +442,113 @@ impl RateLimiter {
     pub(crate) fn get_recent_request_count(&self) -> usize {
         self.last_requests.len()
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::AssertUnwindSafe;
+
+    fn handler() -> InnerApi {
+        Box::new(|_| Response::empty_ok())
+    }
+
+    #[test]
+    fn most_specific_pattern_wins() {
+        let mut apis = ApiRegister::new();
+        apis.register_route(HTTPType::Get, "/api/blog/*rest", handler(), 1, 1);
+        apis.register_route(HTTPType::Get, "/api/blog/:slug", handler(), 1, 1);
+        apis.register_route(HTTPType::Get, "/api/blog/latest", handler(), 1, 1);
+
+        assert_eq!(apis.get_api("/api/blog/latest").unwrap().pattern, "/api/blog/latest");
+        let found = apis.get_api("/api/blog/42").unwrap();
+        assert_eq!(found.pattern, "/api/blog/:slug");
+        assert_eq!(found.params["slug"], "42");
+        assert_eq!(apis.get_api("/api/blog/42/comments").unwrap().pattern, "/api/blog/*rest");
+    }
+
+    #[test]
+    fn more_methods_on_the_same_pattern() {
+        let mut apis = ApiRegister::new();
+        apis.register_route(HTTPType::Get, "/api/blog/:slug", handler(), 1, 1);
+        apis.register_route(HTTPType::Delete, "/api/blog/:slug", handler(), 1, 1);
+        assert_eq!(apis.get_api("/api/blog/42").unwrap().api.allowed_methods(), "GET, HEAD, DELETE, OPTIONS");
+    }
+
+    #[test]
+    #[should_panic(expected = "matches the same paths")]
+    fn params_with_different_names_conflict() {
+        let mut apis = ApiRegister::new();
+        apis.register_route(HTTPType::Get, "/api/:a", handler(), 1, 1);
+        apis.register_route(HTTPType::Post, "/api/:b", handler(), 1, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "matches the same paths")]
+    fn wildcards_with_different_names_conflict() {
+        let mut apis = ApiRegister::new();
+        apis.register_api("/api/files/*path", handler(), 1, 1);
+        apis.register_route(HTTPType::Get, "/api/files/*", handler(), 1, 1);
+    }
+
+    #[test]
+    fn overlapping_patterns_pick_the_same_one_whatever_the_order() {
+        let patterns = ["/api/:a/b", "/api/a/:b", "/api/a/*rest", "/api/:a/*rest"];
+        for first in 0..patterns.len() {
+            let mut apis = ApiRegister::new();
+            for pattern in patterns.iter().cycle().skip(first).take(patterns.len()) {
+                apis.register_route(HTTPType::Get, pattern, handler(), 1, 1);
+            }
+            assert_eq!(apis.get_api("/api/a/b").unwrap().pattern, "/api/a/:b");
+            assert_eq!(apis.get_api("/api/x/b").unwrap().pattern, "/api/:a/b");
+            assert_eq!(apis.get_api("/api/a").unwrap().pattern, "/api/a/*rest");
+            assert_eq!(apis.get_api("/api/x/y/z").unwrap().pattern, "/api/:a/*rest");
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "already has a handler for every method")]
+    fn routes_cant_be_added_to_an_api() {
+        let mut apis = ApiRegister::new();
+        apis.register_api("/api/test", handler(), 1, 1);
+        apis.register_route(HTTPType::Get, "/api/test", handler(), 1, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "is already registered")]
+    fn apis_cant_replace_routes() {
+        let mut apis = ApiRegister::new();
+        apis.register_route(HTTPType::Get, "/api/test", handler(), 1, 1);
+        apis.register_api("/api/test", handler(), 1, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "already has a GET handler")]
+    fn the_same_method_cant_be_registered_twice() {
+        let mut apis = ApiRegister::new();
+        apis.register_route(HTTPType::Get, "/api/test", handler(), 1, 1);
+        apis.register_route(HTTPType::Get, "/api/test", handler(), 1, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "after its wildcard")]
+    fn wildcard_has_to_be_last() {
+        let mut apis = ApiRegister::new();
+        apis.register_route(HTTPType::Get, "/api/*rest/edit", handler(), 1, 1);
+    }
+
+    #[test]
+    fn a_poisoned_user_map_still_works() {
+        let apis = ApiRegister::new();
+        let ip = IpAddr::from([1, 2, 3, 4]);
+        let poisoned = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            let _users = apis.users.write().unwrap();
+            panic!("something went wrong while holding the lock");
+        }));
+        assert!(poisoned.is_err());
+        assert!(apis.users.is_poisoned());
+
+        apis.add_user(ip);
+        assert!(apis.user_exists(&ip));
+        assert!(apis.check_limit(&ip, "/api/test"));
+    }
+}
@@ -0,0 +1,261 @@
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+
+// how much of a file gets read into memory at a time when it cant be sent
+// straight from the kernel
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// what gets sent after the header of a response. Small things are fine as a
+// buffer but files can be massive (the .wasm examples) so they get streamed
+// straight from disk instead of being read into memory first
+pub enum Body {
+    Bytes(Vec<u8>),
+    // offset and length let this be just part of a file for range requests
+    File {
+        file: File,
+        offset: u64,
+        length: u64,
+    },
+    // handed out a chunk at a time, length has to be the total of every chunk
+    Stream {
+        length: u64,
+        chunks: Box<dyn Iterator<Item = Vec<u8>> + Send>,
+    },
+    // for when the length isnt known until its all been made, each chunk
+    // gets sent with its own size and an empty one says its done
+    Chunked(Box<dyn Iterator<Item = Vec<u8>> + Send>),
+}
+
+impl Body {
+    // the whole file from start to end
+    pub fn from_file(file: File) -> Result<Self, io::Error> {
+        let length = file.metadata()?.len();
+        Ok(Self::File {
+            file,
+            offset: 0,
+            length,
+        })
+    }
+
+    // None when the body is chunked and wont know until its done
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Self::Bytes(data) => Some(data.len() as u64),
+            Self::File { length, .. } => Some(*length),
+            Self::Stream { length, .. } => Some(*length),
+            Self::Chunked(_) => None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    // the header is passed in so small bodies can go out in the same write
+    // chunked is false for HTTP/1.0 clients which instead just get the data
+    // untill the connection closes
+    pub fn write_to(self, stream: &mut TcpStream, header: &[u8], chunked: bool) -> Result<(), io::Error> {
+        match self {
+            Self::Bytes(data) => stream.write_all(&[header, &data].concat()),
+            Self::File { mut file, offset, length } => {
+                stream.write_all(header)?;
+                send_file(stream, &mut file, offset, length)
+            },
+            Self::Chunked(chunks) if chunked => {
+                stream.write_all(header)?;
+                // an empty chunk would end the body early so those get skipped
+                for chunk in chunks.filter(|chunk| !chunk.is_empty()) {
+                    let size = format!("{:x}\r\n", chunk.len());
+                    stream.write_all(&[size.as_bytes(), &chunk, b"\r\n"].concat())?;
+                }
+                stream.write_all(b"0\r\n\r\n")
+            },
+            Self::Stream { chunks, .. } | Self::Chunked(chunks) => {
+                stream.write_all(header)?;
+                for chunk in chunks {
+                    stream.write_all(&chunk)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(value: Vec<u8>) -> Self {
+        Self::Bytes(value)
+    }
+}
+
+impl Debug for Body {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bytes(data) => f.debug_tuple("Bytes").field(&data.len()).finish(),
+            Self::File { offset, length, .. } => f.debug_struct("File")
+                .field("offset", offset)
+                .field("length", length)
+                .finish(),
+            Self::Stream { length, .. } => f.debug_struct("Stream")
+                .field("length", length)
+                .finish(),
+            Self::Chunked(_) => f.debug_tuple("Chunked").finish(),
+        }
+    }
+}
+
+// on linux the kernel can copy the file into the socket itself without it
+// ever coming into userspace, anything else gets the read/write loop
+#[cfg(target_os = "linux")]
+fn send_file(stream: &mut TcpStream, file: &mut File, offset: u64, length: u64) -> Result<(), io::Error> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut offset = offset as libc::off_t;
+    let mut remaining = length;
+    while remaining > 0 {
+        let to_send = remaining.min(isize::MAX as u64) as usize;
+        let sent = unsafe {
+            libc::sendfile(stream.as_raw_fd(), file.as_raw_fd(), &mut offset, to_send)
+        };
+
+        match sent {
+            // the file got shorter since we got its length
+            0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            sent if sent > 0 => remaining -= sent as u64,
+            _ => {
+                let error = io::Error::last_os_error();
+                match error.kind() {
+                    io::ErrorKind::Interrupted => continue,
+                    // some file systems cant be used with sendfile at all
+                    _ if remaining == length && is_sendfile_unsupported(&error) => {
+                        return copy_file(stream, file, offset as u64, remaining);
+                    },
+                    _ => return Err(error),
+                }
+            },
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn is_sendfile_unsupported(error: &io::Error) -> bool {
+    matches!(error.raw_os_error(), Some(libc::EINVAL) | Some(libc::ENOSYS))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_file(stream: &mut TcpStream, file: &mut File, offset: u64, length: u64) -> Result<(), io::Error> {
+    copy_file(stream, file, offset, length)
+}
+
+fn copy_file(stream: &mut TcpStream, file: &mut File, offset: u64, length: u64) -> Result<(), io::Error> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut remaining = length;
+    let mut buffer = vec![0_u8; CHUNK_SIZE];
+    while remaining > 0 {
+        let to_read = CHUNK_SIZE.min(remaining as usize);
+        file.read_exact(&mut buffer[..to_read])?;
+        stream.write_all(&buffer[..to_read])?;
+        remaining -= to_read as u64;
+    }
+
+    Ok(())
+}
+
+// multipart/byteranges bodies are a bunch of little headers with parts of a
+// file in between, this reads the file parts only when they are about to be
+// sent so asking for lots of big ranges doesnt fill up memory
+pub struct FileParts {
+    file: File,
+    parts: Vec<FilePart>,
+}
+
+pub enum FilePart {
+    Text(Vec<u8>),
+    // offset and length into the file
+    Range(u64, u64),
+}
+
+impl FileParts {
+    pub fn new(file: File, parts: Vec<FilePart>) -> Self {
+        // parts get popped off of the end so they are flipped here
+        let parts = parts.into_iter().rev().collect();
+        Self {
+            file,
+            parts,
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.parts.iter()
+            .map(|part| match part {
+                FilePart::Text(text) => text.len() as u64,
+                FilePart::Range(_, length) => *length,
+            })
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Iterator for FileParts {
+    type Item = Vec<u8>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.parts.pop()? {
+            FilePart::Text(text) => Some(text),
+            FilePart::Range(offset, length) => {
+                let to_read = length.min(CHUNK_SIZE as u64);
+                let mut buffer = vec![0_u8; to_read as usize];
+                // if the file cant be read theres no way to make the body the
+                // length we promised so just stop and let the connection die
+                self.file.seek(SeekFrom::Start(offset)).ok()?;
+                self.file.read_exact(&mut buffer).ok()?;
+
+                if to_read < length {
+                    self.parts.push(FilePart::Range(offset + to_read, length - to_read));
+                }
+                Some(buffer)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    // what body puts on the wire after an empty header
+    fn sent(body: Body, chunked: bool) -> Vec<u8> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        body.write_to(&mut server, b"", chunked).unwrap();
+        drop(server);
+
+        let mut sent = Vec::new();
+        client.read_to_end(&mut sent).unwrap();
+        sent
+    }
+
+    fn chunks(chunks: &[&str]) -> Body {
+        let chunks = chunks.iter().map(|chunk| chunk.as_bytes().to_vec()).collect::<Vec<Vec<u8>>>();
+        Body::Chunked(Box::new(chunks.into_iter()))
+    }
+
+    #[test]
+    fn chunks_are_sent_with_their_size() {
+        assert_eq!(sent(chunks(&["hello", "", "0123456789abcdef!"]), true), b"5\r\nhello\r\n11\r\n0123456789abcdef!\r\n0\r\n\r\n");
+        assert_eq!(sent(chunks(&[]), true), b"0\r\n\r\n");
+    }
+
+    #[test]
+    fn chunks_are_sent_as_is_when_chunked_cant_be_used() {
+        assert_eq!(sent(chunks(&["hello", " world"]), false), b"hello world");
+        assert_eq!(chunks(&["hello"]).len(), None);
+    }
+}
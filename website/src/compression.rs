@@ -0,0 +1,193 @@
+use std::io::{self, Write};
+use flate2::Compression;
+use flate2::write::{GzEncoder, ZlibEncoder};
+
+// anything smaller than this isnt worth the cpu time, the gzip header alone
+// eats a good chunk of what would be saved
+pub const MIN_COMPRESS_SIZE: usize = 1024;
+
+// bodies compressed on the fly have to fit in memory so big files are only
+// ever sent compressed if there is a precompressed one sitting next to them
+pub const MAX_DYNAMIC_COMPRESS_SIZE: u64 = 2 * 1024 * 1024;
+
+// the order here is the order we prefer them in when the client likes
+// more than one of them the same amount
+pub const DYNAMIC_ENCODINGS: [Encoding; 3] = [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate];
+pub const PRECOMPRESSED_ENCODINGS: [Encoding; 2] = [Encoding::Brotli, Encoding::Gzip];
+
+// brotli's max quality is great for files compressed at build time but way
+// to slow to do on every request
+const BROTLI_DYNAMIC_QUALITY: u32 = 5;
+const BROTLI_WINDOW_SIZE: u32 = 22;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+    Identity,
+}
+
+impl Encoding {
+    // what a precompressed copy of a file would be named with, foo.wasm.br
+    pub fn file_extension(&self) -> Option<&'static str> {
+        match self {
+            Self::Brotli => Some("br"),
+            Self::Gzip => Some("gz"),
+            Self::Deflate => None,
+            Self::Identity => None,
+        }
+    }
+
+    fn matches(&self, token: &str) -> bool {
+        match self {
+            Self::Brotli => token.eq_ignore_ascii_case("br"),
+            Self::Gzip => token.eq_ignore_ascii_case("gzip") || token.eq_ignore_ascii_case("x-gzip"),
+            Self::Deflate => token.eq_ignore_ascii_case("deflate"),
+            Self::Identity => token.eq_ignore_ascii_case("identity"),
+        }
+    }
+}
+
+impl std::fmt::Display for Encoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Brotli => write!(f, "br"),
+            Self::Gzip => write!(f, "gzip"),
+            Self::Deflate => write!(f, "deflate"),
+            Self::Identity => write!(f, "identity"),
+        }
+    }
+}
+
+// picks the encoding out of available the client wants the most based on the
+// elements of its Accept-Encoding header, 'gzip;q=0.8, br, *;q=0.1'
+// identity is what gets sent if nothing else works out
+pub fn negotiate(accept_encoding: &[&str], available: &[Encoding]) -> Encoding {
+    let accepted = accept_encoding.iter()
+        .filter_map(|element| {
+            let mut parts = element.split(';').map(str::trim);
+            let token = parts.next()?;
+            let quality = parts
+                .find_map(|param| param.strip_prefix("q=").or_else(|| param.strip_prefix("Q=")))
+                .map(|q| q.parse::<f32>().unwrap_or(0.0))
+                .unwrap_or(1.0);
+            Some((token, quality))
+        })
+        .collect::<Vec<(&str, f32)>>();
+
+    let wildcard = accepted.iter()
+        .find(|(token, _)| *token == "*")
+        .map(|(_, quality)| *quality);
+
+    let mut best = Encoding::Identity;
+    let mut best_quality = 0.0;
+    for encoding in available {
+        let quality = accepted.iter()
+            .find(|(token, _)| encoding.matches(token))
+            .map(|(_, quality)| *quality)
+            .or(wildcard)
+            .unwrap_or(0.0);
+
+        // strictly greater so ties go to whatever came first in available
+        if quality > best_quality {
+            best = *encoding;
+            best_quality = quality;
+        }
+    }
+
+    best
+}
+
+pub fn compress(data: &[u8], encoding: Encoding) -> Result<Vec<u8>, io::Error> {
+    match encoding {
+        Encoding::Brotli => {
+            let mut output = Vec::new();
+            {
+                let mut writer = brotli::CompressorWriter::new(&mut output, 4096, BROTLI_DYNAMIC_QUALITY, BROTLI_WINDOW_SIZE);
+                writer.write_all(data)?;
+            }
+            Ok(output)
+        },
+        Encoding::Gzip => {
+            let mut writer = GzEncoder::new(Vec::new(), Compression::default());
+            writer.write_all(data)?;
+            writer.finish()
+        },
+        // 'deflate' in http is really the zlib format not raw deflate
+        Encoding::Deflate => {
+            let mut writer = ZlibEncoder::new(Vec::new(), Compression::default());
+            writer.write_all(data)?;
+            writer.finish()
+        },
+        Encoding::Identity => Ok(data.to_vec()),
+    }
+}
+
+// each encoding of a file is a different set of bytes so it needs its own
+// strong etag, "abc" becomes "abc-br"
+pub fn encoded_etag(etag: &str, encoding: Encoding) -> String {
+    match (encoding, etag.strip_suffix('"')) {
+        (Encoding::Identity, _) | (_, None) => etag.to_owned(),
+        (encoding, Some(start)) => format!("{}-{}\"", start, encoding),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use flate2::read::{GzDecoder, ZlibDecoder};
+
+    fn negotiate_header(accept_encoding: &str) -> Encoding {
+        let elements = accept_encoding.split(',').map(str::trim).collect::<Vec<&str>>();
+        negotiate(&elements, &DYNAMIC_ENCODINGS)
+    }
+
+    #[test]
+    fn the_highest_quality_wins() {
+        assert_eq!(negotiate_header("gzip, deflate, br"), Encoding::Brotli);
+        assert_eq!(negotiate_header("gzip;q=1.0, br;q=0.5"), Encoding::Gzip);
+        assert_eq!(negotiate_header("deflate"), Encoding::Deflate);
+        assert_eq!(negotiate_header("x-gzip"), Encoding::Gzip);
+        assert_eq!(negotiate_header("*;q=0.5, br;q=0"), Encoding::Gzip);
+    }
+
+    #[test]
+    fn identity_is_used_when_nothing_else_is_wanted() {
+        assert_eq!(negotiate(&[], &DYNAMIC_ENCODINGS), Encoding::Identity);
+        assert_eq!(negotiate_header("identity"), Encoding::Identity);
+        assert_eq!(negotiate_header("gzip;q=0, br;q=0, deflate;q=0"), Encoding::Identity);
+        assert_eq!(negotiate_header("zstd"), Encoding::Identity);
+        // nothing available at all
+        assert_eq!(negotiate(&["gzip"], &[]), Encoding::Identity);
+    }
+
+    #[test]
+    fn compressed_data_comes_back_the_same() {
+        let data = "the same line over and over\n".repeat(200).into_bytes();
+
+        let mut output = Vec::new();
+        GzDecoder::new(compress(&data, Encoding::Gzip).unwrap().as_slice()).read_to_end(&mut output).unwrap();
+        assert_eq!(output, data);
+
+        let mut output = Vec::new();
+        ZlibDecoder::new(compress(&data, Encoding::Deflate).unwrap().as_slice()).read_to_end(&mut output).unwrap();
+        assert_eq!(output, data);
+
+        let compressed = compress(&data, Encoding::Brotli).unwrap();
+        assert!(compressed.len() < data.len());
+        let mut output = Vec::new();
+        brotli::Decompressor::new(compressed.as_slice(), 4096).read_to_end(&mut output).unwrap();
+        assert_eq!(output, data);
+
+        assert_eq!(compress(&data, Encoding::Identity).unwrap(), data);
+    }
+
+    #[test]
+    fn each_encoding_gets_its_own_etag() {
+        assert_eq!(encoded_etag("\"abc\"", Encoding::Brotli), "\"abc-br\"");
+        assert_eq!(encoded_etag("\"abc\"", Encoding::Gzip), "\"abc-gzip\"");
+        assert_eq!(encoded_etag("\"abc\"", Encoding::Identity), "\"abc\"");
+    }
+}
@@ -0,0 +1,841 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Cursor, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use crate::framing::{Progress, RequestScanner};
+use crate::shutdown;
+use crate::timeout::{self, ConnectionTimeouts, HeaderDeadline, SlowStep, TimedStream};
+use crate::types::{turn_system_time_to_http_date, HTTPError, Request, RequestLimits, Response};
+
+// every connection sits in here while its waiting on the client, so one
+// thread can keep thousands of idle keep-alive connections around. Only once
+// a whole request has come in does the connection go off to be served, and
+// it comes back here afterwards if its being kept alive
+
+#[derive(Debug, Clone)]
+pub struct EventLoopConfig {
+    pub timeouts: ConnectionTimeouts,
+    pub limits: RequestLimits,
+    // open connections at once, counting ones being served, new ones wait in
+    // the os backlog past this. Keep it well under the open file limit so
+    // theres still room to open the files being sent
+    pub max_connections: usize,
+    // how long connections that are part way through a request get to
+    // finish sending it once the server is shutting down
+    pub shutdown_timeout: Duration,
+}
+
+impl Default for EventLoopConfig {
+    fn default() -> Self {
+        Self {
+            timeouts: ConnectionTimeouts::default(),
+            limits: RequestLimits::default(),
+            max_connections: 10_000,
+            shutdown_timeout: Duration::from_secs(20),
+        }
+    }
+}
+
+// the listener and waker get the first two tokens, connections get the rest
+const LISTENER: u64 = 0;
+const WAKER: u64 = 1;
+const FIRST_CONNECTION: u64 = 2;
+
+const MAX_EVENTS: usize = 256;
+const READ_SIZE: usize = 16 * 1024;
+// deadlines are all in seconds so checking them this often is plenty
+const SWEEP_INTERVAL: Duration = Duration::from_millis(250);
+// how often dispatch gets asked again about requests it had no room for
+const HELD_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+// gets each connection with a whole request in it, see run
+type Dispatch<'a> = dyn FnMut(Connection) -> Option<Connection> + 'a;
+
+// a connection with a whole request ready to be served. Its blocking while
+// its out of the event loop so responses get written like they always were,
+// with the write timeout that was set when it was accepted
+#[derive(Debug)]
+pub struct Connection {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    // how far into buffer the request has been looked at
+    scanner: RequestScanner,
+    request_length: usize,
+    // the request is an upload and only its header is in buffer, the body
+    // is still on its way and gets read as its parsed
+    streamed: bool,
+    // for reading the body of an upload once its out of the event loop
+    timeouts: ConnectionTimeouts,
+    requests: usize,
+    home: Sender<Connection>,
+    waker: Arc<Waker>,
+    _open: OpenConnection,
+}
+
+impl Connection {
+    // the bytes of the request, pipelined ones after it stay buffered. For
+    // an upload this is only the header, use read_request to get all of it
+    pub fn get_request(&self) -> &[u8] {
+        &self.buffer[..self.request_length]
+    }
+
+    // parses the request, reading the rest of an upload off the connection
+    // as it goes. Whatever comes after it stays buffered for keep_alive
+    pub fn read_request(&mut self, limits: &RequestLimits) -> Result<Request, HTTPError> {
+        if !self.streamed {
+            return Request::new(&mut self.get_request(), limits);
+        }
+
+        // the body is read blocking with the same deadlines a request on a
+        // thread of its own gets, the header already arrived in time
+        let stream = match self.stream.try_clone().and_then(|stream| TimedStream::new(stream, self.timeouts)) {
+            Ok(stream) => stream,
+            Err(_) => return Err(HTTPError::ConnectionClosed),
+        };
+        let buffered = Cursor::new(std::mem::take(&mut self.buffer));
+        let mut reader = BufReader::new(BufferedStream { buffered, stream });
+        let request = Request::new(&mut reader, limits);
+
+        // anything read past the end of the upload is the start of the next request
+        let mut leftover = reader.buffer().to_vec();
+        let BufferedStream { buffered, .. } = reader.into_inner();
+        let position = buffered.position() as usize;
+        leftover.extend_from_slice(&buffered.into_inner()[position..]);
+        self.buffer = leftover;
+        self.request_length = 0;
+        self.streamed = false;
+        request
+    }
+
+    // 1 for the first request on the connection
+    pub fn get_request_count(&self) -> usize {
+        self.requests
+    }
+
+    pub fn get_stream(&mut self) -> &mut TcpStream {
+        &mut self.stream
+    }
+
+    // hands the connection back to the event loop to wait for the next
+    // request, once the loop has stopped it just gets closed
+    pub fn keep_alive(mut self) {
+        // the rest of an upload that was never read would look like the next request
+        if self.streamed {
+            return;
+        }
+        self.buffer.drain(..self.request_length);
+        self.scanner = RequestScanner::default();
+        self.request_length = 0;
+        if let Err(e) = self.stream.set_nonblocking(true) {
+            println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+            return;
+        }
+
+        let waker = Arc::clone(&self.waker);
+        let home = self.home.clone();
+        if home.send(self).is_ok() {
+            waker.wake();
+        }
+    }
+
+    // for when the connection wont be served at all, like when the pool is full
+    pub fn into_stream(self) -> TcpStream {
+        self.stream
+    }
+
+    // reads whatever the client has sent so far, stopping as soon as theres
+    // a whole request so pipelining cant grow the buffer forever. Ok(false)
+    // if the client hung up
+    fn fill_buffer(&mut self, limits: &RequestLimits) -> Result<bool, io::Error> {
+        let mut chunk = [0; READ_SIZE];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Ok(false),
+                Ok(read) => {
+                    self.buffer.extend_from_slice(&chunk[..read]);
+                    match self.scanner.scan(&self.buffer, limits) {
+                        Ok(Progress::Empty | Progress::Header | Progress::Body) => {},
+                        _ => return Ok(true),
+                    }
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(true),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // best effort as the socket is nonblocking, a client too slow to take
+    // a few hundred bytes doesnt get waited on
+    fn send_error(&mut self, error: HTTPError) {
+        if let Err(e) = Response::new_error(error).write_to(&mut self.stream) {
+            println!("Error sending response: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+        }
+    }
+}
+
+// counts connections from accept until they get closed, wherever that is
+// the part of an upload the event loop already read and then the rest of it
+// straight off the connection
+struct BufferedStream {
+    buffered: Cursor<Vec<u8>>,
+    stream: TimedStream,
+}
+
+impl Read for BufferedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.buffered.fill_buf()?.is_empty() {
+            true => self.stream.read(buf),
+            false => self.buffered.read(buf),
+        }
+    }
+}
+
+impl HeaderDeadline for BufferedStream {
+    fn header_received(&mut self) {
+        self.stream.header_received();
+    }
+}
+
+#[derive(Debug)]
+struct OpenConnection(Arc<AtomicUsize>);
+
+impl OpenConnection {
+    fn new(open: &Arc<AtomicUsize>) -> Self {
+        open.fetch_add(1, Ordering::SeqCst);
+        Self(Arc::clone(open))
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// an eventfd the workers poke when they send a connection back, so the loop
+// doesnt have to wait until the next sweep to notice
+#[derive(Debug)]
+struct Waker(File);
+
+impl Waker {
+    fn new() -> Result<Self, io::Error> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self(unsafe { File::from_raw_fd(fd) }))
+    }
+
+    fn wake(&self) {
+        // only fails when the counter is full which still wakes the loop
+        let _ = (&self.0).write(&1_u64.to_ne_bytes());
+    }
+
+    fn reset(&self) {
+        let mut count = [0; 8];
+        let _ = (&self.0).read(&mut count);
+    }
+}
+
+impl AsRawFd for Waker {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+// level triggered so anything not read yet just comes up again next wait
+struct Epoll(File);
+
+impl Epoll {
+    fn new() -> Result<Self, io::Error> {
+        let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self(unsafe { File::from_raw_fd(fd) }))
+    }
+
+    fn add(&self, fd: RawFd, token: u64) -> Result<(), io::Error> {
+        let mut event = libc::epoll_event {
+            events: (libc::EPOLLIN | libc::EPOLLRDHUP) as u32,
+            u64: token,
+        };
+        self.control(libc::EPOLL_CTL_ADD, fd, &mut event)
+    }
+
+    fn delete(&self, fd: RawFd) -> Result<(), io::Error> {
+        // older kernels want an event even though its ignored
+        let mut event = libc::epoll_event { events: 0, u64: 0 };
+        self.control(libc::EPOLL_CTL_DEL, fd, &mut event)
+    }
+
+    fn control(&self, op: libc::c_int, fd: RawFd, event: &mut libc::epoll_event) -> Result<(), io::Error> {
+        match unsafe { libc::epoll_ctl(self.0.as_raw_fd(), op, fd, event) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    // the tokens of everything that became ready, a signal interrupting the
+    // wait just means no events
+    fn wait(&self, events: &mut [libc::epoll_event], timeout: Duration) -> Result<Vec<u64>, io::Error> {
+        // rounded up so a wait for less than a millisecond doesnt spin
+        let timeout = timeout.as_micros().div_ceil(1000).min(libc::c_int::MAX as u128) as libc::c_int;
+        let ready = unsafe {
+            libc::epoll_wait(self.0.as_raw_fd(), events.as_mut_ptr(), events.len() as libc::c_int, timeout)
+        };
+        if ready < 0 {
+            let error = io::Error::last_os_error();
+            return match error.kind() {
+                ErrorKind::Interrupted => Ok(Vec::new()),
+                _ => Err(error),
+            };
+        }
+        Ok(events[..ready as usize].iter().map(|event| event.u64).collect())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Phase {
+    // waiting for the first byte of a request, until the deadline
+    Idle(Instant),
+    Header(Instant),
+    Body(Instant),
+}
+
+// a connection the loop is waiting on
+struct Waiting {
+    connection: Connection,
+    phase: Phase,
+    last_read: Instant,
+}
+
+impl Waiting {
+    fn new(connection: Connection, timeouts: &ConnectionTimeouts) -> Self {
+        let now = Instant::now();
+        Self {
+            connection,
+            phase: Phase::Idle(now + timeouts.idle),
+            last_read: now,
+        }
+    }
+
+    // same as TimedStream, the deadline for the current part of the request
+    // and the read timeout between each bit of it. None if theres time left
+    fn timed_out(&self, timeouts: &ConnectionTimeouts, now: Instant) -> Option<Phase> {
+        let expired = match self.phase {
+            Phase::Idle(deadline) => now >= deadline,
+            Phase::Header(deadline) | Phase::Body(deadline) => {
+                now >= deadline || now >= self.last_read + timeouts.read
+            },
+        };
+        match expired {
+            true => Some(self.phase),
+            false => None,
+        }
+    }
+}
+
+struct EventLoop {
+    epoll: Epoll,
+    waker: Arc<Waker>,
+    home: Sender<Connection>,
+    config: EventLoopConfig,
+    listener: Option<TcpListener>,
+    accepting: bool,
+    // accept stops for a bit after running out of file descriptors
+    paused_until: Option<Instant>,
+    open: Arc<AtomicUsize>,
+    waiting: HashMap<u64, Waiting>,
+    // whole requests dispatch had no room for yet, they go first once theres
+    // room and nothing new gets accepted until then
+    held: VecDeque<Connection>,
+    next_token: u64,
+}
+
+// runs until the server shuts down and every connection that was part way
+// through a request is done with it (or shutdown_timeout passes). dispatch
+// gets each connection with a whole request in it and is called on the loop
+// thread, so it has to hand the connection off instead of serving it. If
+// theres nowhere to hand it off to it can give the connection back, the
+// loop stops accepting and tries it again a little later
+pub fn run<F: FnMut(Connection) -> Option<Connection>>(listener: TcpListener, config: EventLoopConfig, mut dispatch: F) -> Result<(), io::Error> {
+    listener.set_nonblocking(true)?;
+    raise_file_limit();
+
+    let epoll = Epoll::new()?;
+    let waker = Arc::new(Waker::new()?);
+    epoll.add(listener.as_raw_fd(), LISTENER)?;
+    epoll.add(waker.as_raw_fd(), WAKER)?;
+    let (home, returned) = mpsc::channel();
+
+    let mut event_loop = EventLoop {
+        epoll,
+        waker,
+        home,
+        config,
+        listener: Some(listener),
+        accepting: true,
+        paused_until: None,
+        open: Arc::new(AtomicUsize::new(0)),
+        waiting: HashMap::new(),
+        held: VecDeque::new(),
+        next_token: FIRST_CONNECTION,
+    };
+    event_loop.run(&returned, &mut dispatch)
+}
+
+impl EventLoop {
+    fn run(&mut self, returned: &Receiver<Connection>, dispatch: &mut Dispatch<'_>) -> Result<(), io::Error> {
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+        let mut next_sweep = Instant::now() + SWEEP_INTERVAL;
+        let mut shutdown_deadline = None;
+
+        loop {
+            if shutdown::is_shutting_down() && shutdown_deadline.is_none() {
+                let started = shutdown::started().unwrap_or_else(Instant::now);
+                shutdown_deadline = Some(started + self.config.shutdown_timeout);
+                self.stop_listening();
+            }
+            if let Some(deadline) = shutdown_deadline {
+                let unfinished = self.waiting.len() + self.held.len();
+                if unfinished == 0 {
+                    return Ok(());
+                }
+                if Instant::now() >= deadline {
+                    println!("Closing {} connections that didnt get their request served in time", unfinished);
+                    return Ok(());
+                }
+            }
+
+            self.retry_held(dispatch);
+            let mut timeout = next_sweep.saturating_duration_since(Instant::now());
+            if !self.held.is_empty() {
+                timeout = timeout.min(HELD_RETRY_INTERVAL);
+            }
+            for token in self.epoll.wait(&mut events, timeout)? {
+                match token {
+                    LISTENER => self.accept_all(dispatch),
+                    WAKER => {
+                        self.waker.reset();
+                        while let Ok(connection) = returned.try_recv() {
+                            let waiting = Waiting::new(connection, &self.config.timeouts);
+                            self.advance(waiting, dispatch);
+                        }
+                    },
+                    token => self.read(token, dispatch),
+                }
+            }
+
+            let now = Instant::now();
+            if now >= next_sweep {
+                self.sweep(now);
+                self.resume_accepting(now);
+                next_sweep = now + SWEEP_INTERVAL;
+            }
+        }
+    }
+
+    fn accept_all(&mut self, dispatch: &mut Dispatch<'_>) {
+        loop {
+            if self.open.load(Ordering::SeqCst) >= self.config.max_connections {
+                self.pause_accepting(None);
+                return;
+            }
+
+            let accepted = match &self.listener {
+                Some(listener) => listener.accept(),
+                None => return,
+            };
+            let stream = match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    println!("Error: {}, \n occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+                    // out of file descriptors, the listener would just keep
+                    // coming up ready until some get closed
+                    if matches!(e.raw_os_error(), Some(libc::EMFILE | libc::ENFILE)) {
+                        self.pause_accepting(Some(Instant::now() + Duration::from_secs(1)));
+                    }
+                    return;
+                },
+            };
+
+            match self.connection(stream) {
+                Ok(connection) => {
+                    let waiting = Waiting::new(connection, &self.config.timeouts);
+                    self.advance(waiting, dispatch);
+                },
+                Err(e) => println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now())),
+            }
+        }
+    }
+
+    fn connection(&self, stream: TcpStream) -> Result<Connection, io::Error> {
+        stream.set_nonblocking(true)?;
+        stream.set_write_timeout(Some(self.config.timeouts.write))?;
+        Response::prepare_stream(&stream)?;
+        Ok(Connection {
+            stream,
+            buffer: Vec::new(),
+            scanner: RequestScanner::default(),
+            request_length: 0,
+            streamed: false,
+            timeouts: self.config.timeouts,
+            requests: 0,
+            home: self.home.clone(),
+            waker: Arc::clone(&self.waker),
+            _open: OpenConnection::new(&self.open),
+        })
+    }
+
+    fn read(&mut self, token: u64, dispatch: &mut Dispatch<'_>) {
+        // could have been closed earlier in the same batch of events
+        let mut waiting = match self.waiting.remove(&token) {
+            Some(waiting) => waiting,
+            None => return,
+        };
+        let buffered = waiting.connection.buffer.len();
+        let open = match waiting.connection.fill_buffer(&self.config.limits) {
+            Ok(open) => open,
+            Err(_) => {
+                self.close(waiting);
+                return;
+            },
+        };
+        if waiting.connection.buffer.len() > buffered {
+            waiting.last_read = Instant::now();
+        }
+        self.register(token, waiting, open, dispatch);
+    }
+
+    // a connection the loop hasnt been watching, either just accepted or
+    // back from being served
+    fn advance(&mut self, waiting: Waiting, dispatch: &mut Dispatch<'_>) {
+        let token = self.next_token;
+        self.next_token += 1;
+        match self.epoll.add(waiting.connection.stream.as_raw_fd(), token) {
+            Ok(()) => self.register(token, waiting, true, dispatch),
+            Err(e) => println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now())),
+        }
+    }
+
+    // works out what to do with a connection after more of the request came
+    // in, which is either to serve it, hang up or keep waiting. Its already
+    // registered with epoll under token
+    fn register(&mut self, token: u64, mut waiting: Waiting, open: bool, dispatch: &mut Dispatch<'_>) {
+        let now = Instant::now();
+        let timeouts = self.config.timeouts;
+        let connection = &mut waiting.connection;
+        match connection.scanner.scan(&connection.buffer, &self.config.limits) {
+            Ok(Progress::Complete(length)) => self.hand_off(waiting, length, false, dispatch),
+            Ok(Progress::Streamed(length)) => self.hand_off(waiting, length, true, dispatch),
+            Err(e) => {
+                println!("Error: {}, occured at: {:?}", e, turn_system_time_to_http_date(SystemTime::now()));
+                // whatever follows in the buffer cant be trusted to line up
+                // with a request, the client gets told why and is hung up on
+                waiting.connection.send_error(e);
+                self.close(waiting);
+            },
+            // half a request from a client that hung up is never getting finished
+            Ok(_) if !open => self.close(waiting),
+            Ok(Progress::Empty) if shutdown::is_shutting_down() => self.close(waiting),
+            Ok(progress) => {
+                waiting.phase = match (progress, waiting.phase) {
+                    (Progress::Empty, phase) => {
+                        // only ever empty lines so theres nothing to keep
+                        waiting.connection.buffer.clear();
+                        waiting.connection.scanner = RequestScanner::default();
+                        phase
+                    },
+                    (Progress::Header, Phase::Idle(_)) => Phase::Header(now + timeouts.header),
+                    (Progress::Body, Phase::Idle(_) | Phase::Header(_)) => Phase::Body(now + timeouts.body),
+                    (_, phase) => phase,
+                };
+                self.waiting.insert(token, waiting);
+            },
+        }
+    }
+
+    // the request is ready to be served so the connection leaves the loop,
+    // streamed is for an upload whose body hasnt all come in yet
+    fn hand_off(&mut self, waiting: Waiting, length: usize, streamed: bool, dispatch: &mut Dispatch<'_>) {
+        self.deregister(&waiting);
+        let mut connection = waiting.connection;
+        if let Err(e) = connection.stream.set_nonblocking(false) {
+            println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+            return;
+        }
+        connection.request_length = length;
+        connection.streamed = streamed;
+        connection.requests += 1;
+        self.dispatch(connection, dispatch);
+    }
+
+    fn dispatch(&mut self, connection: Connection, dispatch: &mut Dispatch<'_>) {
+        // anything already held was first
+        if !self.held.is_empty() {
+            self.held.push_back(connection);
+            return;
+        }
+        if let Some(connection) = dispatch(connection) {
+            self.held.push_back(connection);
+            self.pause_accepting(None);
+        }
+    }
+
+    fn retry_held(&mut self, dispatch: &mut Dispatch<'_>) {
+        while let Some(connection) = self.held.pop_front() {
+            if let Some(connection) = dispatch(connection) {
+                self.held.push_front(connection);
+                return;
+            }
+        }
+        self.resume_accepting(Instant::now());
+    }
+
+    fn deregister(&self, waiting: &Waiting) {
+        if let Err(e) = self.epoll.delete(waiting.connection.stream.as_raw_fd()) {
+            println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+        }
+    }
+
+    // dropping the stream closes it, which takes it out of epoll as well
+    fn close(&self, waiting: Waiting) {
+        drop(waiting);
+    }
+
+    // hangs up on everyone who took too long, with a 408 for the ones part
+    // way through a request
+    fn sweep(&mut self, now: Instant) {
+        let timeouts = self.config.timeouts;
+        let expired = self.waiting.iter()
+            .filter_map(|(token, waiting)| Some((*token, waiting.timed_out(&timeouts, now)?)))
+            .collect::<Vec<(u64, Phase)>>();
+
+        for (token, phase) in expired {
+            let mut waiting = match self.waiting.remove(&token) {
+                Some(waiting) => waiting,
+                None => continue,
+            };
+            match phase {
+                // only counted when nothing was ever sent, same as
+                // TimedStream::record_timeout
+                Phase::Idle(_) => {
+                    if waiting.connection.requests == 0 {
+                        timeout::record_slow_drop(SlowStep::Idle);
+                    }
+                },
+                Phase::Header(_) => {
+                    timeout::record_slow_drop(SlowStep::Header);
+                    waiting.connection.send_error(HTTPError::RequestTimeout);
+                },
+                Phase::Body(_) => {
+                    timeout::record_slow_drop(SlowStep::Body);
+                    waiting.connection.send_error(HTTPError::RequestTimeout);
+                },
+            }
+            self.close(waiting);
+        }
+    }
+
+    fn pause_accepting(&mut self, until: Option<Instant>) {
+        self.paused_until = until;
+        if !self.accepting {
+            return;
+        }
+        if let Some(listener) = &self.listener {
+            if let Err(e) = self.epoll.delete(listener.as_raw_fd()) {
+                println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+                return;
+            }
+        }
+        self.accepting = false;
+    }
+
+    // after a pause for running out of file descriptors, once connections
+    // have closed after hitting max_connections or once dispatch has taken
+    // everything that was held
+    fn resume_accepting(&mut self, now: Instant) {
+        if self.accepting || !self.held.is_empty() {
+            return;
+        }
+        let waited = self.paused_until.is_none_or(|until| now >= until);
+        if !waited || self.open.load(Ordering::SeqCst) >= self.config.max_connections {
+            return;
+        }
+        if let Some(listener) = &self.listener {
+            if let Err(e) = self.epoll.add(listener.as_raw_fd(), LISTENER) {
+                println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+                return;
+            }
+            self.accepting = true;
+        }
+    }
+
+    // without the listener the os refuses new connections for us rather than
+    // letting them queue up while we finish. Idle connections get closed too,
+    // the ones part way through a request get to finish it
+    fn stop_listening(&mut self) {
+        if let Some(listener) = self.listener.take() {
+            if self.accepting {
+                let _ = self.epoll.delete(listener.as_raw_fd());
+            }
+            self.accepting = false;
+        }
+
+        let idle = self.waiting.iter()
+            .filter(|(_, waiting)| matches!(waiting.phase, Phase::Idle(_)))
+            .map(|(token, _)| *token)
+            .collect::<Vec<u64>>();
+        for token in idle {
+            if let Some(waiting) = self.waiting.remove(&token) {
+                self.close(waiting);
+            }
+        }
+    }
+}
+
+// the default limit of 1024 open files is nowhere near enough for lots of
+// idle connections, the hard limit is usually way higher
+fn raise_file_limit() {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return;
+    }
+    if limit.rlim_cur >= limit.rlim_max {
+        return;
+    }
+    limit.rlim_cur = limit.rlim_max;
+    match unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } {
+        0 => println!("Raised the open file limit to {}", limit.rlim_max),
+        _ => println!("Error: {}, couldnt raise the open file limit", io::Error::last_os_error()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use std::thread;
+    use crate::types::ContentType;
+
+    const SHORT: ConnectionTimeouts = ConnectionTimeouts {
+        idle: Duration::from_millis(300),
+        read: Duration::from_millis(300),
+        write: Duration::from_millis(300),
+        header: Duration::from_millis(300),
+        body: Duration::from_millis(300),
+    };
+
+    // a loop on its own port that answers every request with 'ok', it keeps
+    // running until the tests are done as stopping it would mean shutting
+    // down everything else too
+    fn start() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let config = EventLoopConfig {
+            timeouts: SHORT,
+            ..EventLoopConfig::default()
+        };
+        thread::spawn(move || {
+            run(listener, config, |mut connection| {
+                let response = Response::new_ok(ContentType::PlainText, None, b"ok".to_vec());
+                response.write_to(connection.get_stream()).unwrap();
+                None
+            })
+        });
+        address
+    }
+
+    fn response_to(address: SocketAddr, request: &[u8]) -> String {
+        let mut client = TcpStream::connect(address).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(request).unwrap();
+        let mut response = Vec::new();
+        let _ = client.read_to_end(&mut response);
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    #[test]
+    fn whole_requests_are_dispatched() {
+        let response = response_to(start(), b"GET / HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("ok"));
+    }
+
+    #[test]
+    fn a_stalled_header_is_a_408() {
+        let started = Instant::now();
+        let response = response_to(start(), b"GET / HTTP/1.1\r\nX-Forw");
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"), "{}", response);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn a_stalled_body_is_a_408() {
+        let response = response_to(start(), b"POST / HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\nContent-Length: 10\r\n\r\nabc");
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"), "{}", response);
+    }
+
+    // the upload is only answered once its all been read, which can only
+    // happen if it was handed off before the body came in
+    #[test]
+    fn uploads_are_read_off_the_connection_once_the_header_is_in() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let config = EventLoopConfig {
+            timeouts: SHORT,
+            ..EventLoopConfig::default()
+        };
+        let limits = config.limits.clone();
+        let (dispatched, was_dispatched) = mpsc::channel();
+        thread::spawn(move || {
+            run(listener, config, |mut connection| {
+                let _ = dispatched.send(());
+                // blocking on the loop thread is fine for a test with one client
+                let request = connection.read_request(&limits).unwrap();
+                let data = match request.get_multipart() {
+                    Ok(form) => form.text("email").unwrap_or("").as_bytes().to_vec(),
+                    Err(_) => request.get_path().as_bytes().to_vec(),
+                };
+                let response = Response::new_ok(ContentType::PlainText, None, data);
+                response.write_to(connection.get_stream()).unwrap();
+                connection.keep_alive();
+                None
+            })
+        });
+
+        let body = "--XyZ\r\nContent-Disposition: form-data; name=\"email\"\r\n\r\na@b.c\r\n--XyZ--\r\n";
+        let header = format!("POST /api/mail HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\nContent-Type: multipart/form-data; boundary=XyZ\r\nContent-Length: {}\r\n\r\n", body.len());
+        let mut client = TcpStream::connect(address).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(header.as_bytes()).unwrap();
+        was_dispatched.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        let next = "GET /next HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\nConnection: close\r\n\r\n";
+        client.write_all(format!("{}{}", body, next).as_bytes()).unwrap();
+        let mut response = Vec::new();
+        let _ = client.read_to_end(&mut response);
+        let response = String::from_utf8_lossy(&response);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("a@b.c"), "{}", response);
+        assert!(response.ends_with("/next"), "{}", response);
+    }
+
+    #[test]
+    fn an_idle_connection_is_closed_without_a_response() {
+        let response = response_to(start(), b"");
+        assert_eq!(response, "");
+    }
+}
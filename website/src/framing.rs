@@ -0,0 +1,374 @@
+use std::str::FromStr;
+use crate::headers::Headers;
+use crate::mime::Mime;
+use crate::types::{content_length, parse_chunk_size, HTTPError, RequestLimits, MAX_CHUNK_SIZE_LINE};
+
+// how much of the first request in a buffer has arrived so far
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Progress {
+    // nothing, or only the empty lines clients are allowed to send between requests
+    Empty,
+    // part way through the request line or the header
+    Header,
+    // the header is all there but the body isnt yet
+    Body,
+    // the whole request is there and takes up this many bytes
+    Complete(usize),
+    // the header is all there and takes up this many bytes, the body is an
+    // upload thats read as its parsed instead of waiting for all of it
+    Streamed(usize),
+}
+
+// works out where the first request in buffer ends without parsing it into
+// a Request, so bytes can be collected as they come in and only handed off
+// once the whole thing is there. This goes over the limits the same way
+// Request::new does but as soon as its clear they were broken, instead of
+// after buffering all of it.
+// The buffer is only ever added to between scans so each one carries on
+// from where the last one stopped, a big request coming in a bit at a time
+// doesnt get looked at from the start again after every read. Once the
+// buffer is emptied or the request taken off the front it needs a new one
+#[derive(Debug, Clone, Default)]
+pub struct RequestScanner {
+    step: Step,
+    lines: Lines,
+}
+
+// which part of the request the scanner is up to, positions are all from
+// the start of the buffer
+#[derive(Debug, Clone, Copy, Default)]
+enum Step {
+    #[default]
+    Start,
+    RequestLine { start: usize },
+    Header { start: usize, count: usize },
+    Body { end: usize },
+    ChunkSize { body_size: usize },
+    // end is just past the \r\n after the chunk
+    Chunk { body_size: usize, end: usize },
+    Trailers { start: usize, count: usize },
+    Complete(usize),
+    Streamed(usize),
+    // the request was broken, anything after this in the buffer is too
+    Failed(HTTPError),
+}
+
+// where the line being looked at starts and how far its been searched for
+// its end, so a half sent line isnt searched again from its start
+#[derive(Debug, Clone, Copy, Default)]
+struct Lines {
+    position: usize,
+    searched: usize,
+}
+
+impl RequestScanner {
+    pub fn scan(&mut self, buffer: &[u8], limits: &RequestLimits) -> Result<Progress, HTTPError> {
+        if let Step::Failed(error) = self.step {
+            return Err(error);
+        }
+        // the lines already went past whatever broke the limits so it has
+        // to be remembered, scanning again wouldnt see it
+        let progress = self.carry_on(buffer, limits);
+        if let Err(error) = progress {
+            self.step = Step::Failed(error);
+        }
+        progress
+    }
+
+    fn carry_on(&mut self, buffer: &[u8], limits: &RequestLimits) -> Result<Progress, HTTPError> {
+        let lines = &mut self.lines;
+        loop {
+            let next = match &mut self.step {
+                Step::Start => match buffer[lines.position..].iter().position(|byte| !matches!(byte, b'\r' | b'\n')) {
+                    Some(skipped) => {
+                        lines.skip_to(lines.position + skipped);
+                        Step::RequestLine { start: lines.position }
+                    },
+                    None => {
+                        lines.skip_to(buffer.len());
+                        return Ok(Progress::Empty);
+                    },
+                },
+                Step::RequestLine { start } => match lines.next(buffer) {
+                    Some(end) if end - *start > limits.max_request_line => return Err(HTTPError::RequestLineTooLong),
+                    Some(end) => Step::Header { start: end, count: 0 },
+                    None if buffer.len() - *start >= limits.max_request_line => return Err(HTTPError::RequestLineTooLong),
+                    None => return Ok(Progress::Header),
+                },
+                Step::Header { start, count } => match scan_header_block(lines, buffer, *start, count, limits)? {
+                    Some(end) => body_step(&buffer[*start..end], end, limits)?,
+                    None => return Ok(Progress::Header),
+                },
+                Step::Body { end } => match *end <= buffer.len() {
+                    true => Step::Complete(*end),
+                    false => return Ok(Progress::Body),
+                },
+                Step::ChunkSize { body_size } => {
+                    let line_start = lines.position;
+                    let line_end = match lines.next(buffer) {
+                        Some(end) if end - line_start > MAX_CHUNK_SIZE_LINE => return Err(HTTPError::InvalidContent),
+                        Some(end) => end,
+                        None if buffer.len() - line_start >= MAX_CHUNK_SIZE_LINE => return Err(HTTPError::InvalidContent),
+                        None => return Ok(Progress::Body),
+                    };
+
+                    // see read_chunked_body for the format
+                    match parse_chunk_size(&buffer[line_start..line_end], *body_size as u64, limits.max_body_size as u64)? {
+                        0 => Step::Trailers { start: line_end, count: 0 },
+                        size => {
+                            // the size fits in a usize now that its under the body limit
+                            let size = size as usize;
+                            match line_end.checked_add(size).and_then(|end| end.checked_add(2)) {
+                                Some(end) => Step::Chunk { body_size: *body_size + size, end },
+                                None => return Err(HTTPError::ContentTooLarge),
+                            }
+                        },
+                    }
+                },
+                Step::Chunk { body_size, end } => {
+                    if buffer.len() < *end {
+                        return Ok(Progress::Body);
+                    }
+                    if &buffer[*end - 2..*end] != b"\r\n" {
+                        return Err(HTTPError::InvalidContent);
+                    }
+                    lines.skip_to(*end);
+                    Step::ChunkSize { body_size: *body_size }
+                },
+                Step::Trailers { start, count } => match scan_header_block(lines, buffer, *start, count, limits)? {
+                    Some(end) => Step::Complete(end),
+                    None => return Ok(Progress::Body),
+                },
+                Step::Complete(end) => return Ok(Progress::Complete(*end)),
+                Step::Streamed(end) => return Ok(Progress::Streamed(*end)),
+                Step::Failed(error) => return Err(*error),
+            };
+            self.step = next;
+        }
+    }
+}
+
+impl Lines {
+    // the index just past the end of the current line, which the next one
+    // then starts at
+    fn next(&mut self, buffer: &[u8]) -> Option<usize> {
+        let from = self.searched.max(self.position);
+        match buffer[from..].iter().position(|byte| *byte == b'\n') {
+            Some(found) => {
+                self.skip_to(from + found + 1);
+                Some(self.position)
+            },
+            None => {
+                self.searched = buffer.len();
+                None
+            },
+        }
+    }
+
+    fn skip_to(&mut self, position: usize) {
+        self.position = position;
+        self.searched = position;
+    }
+}
+
+// how the body is sent, decided once the whole header is there
+fn body_step(header: &[u8], header_end: usize, limits: &RequestLimits) -> Result<Step, HTTPError> {
+    let headers = match std::str::from_utf8(header) {
+        Ok(header) => Headers::from_str(header)?,
+        Err(_) => return Err(HTTPError::InvalidHeader),
+    };
+
+    // uploads can be far bigger than anything else so theyre never all
+    // buffered, Request::new reads them straight off the connection
+    let upload = headers.get("Content-Type")
+        .and_then(|value| Mime::from_str(value).ok())
+        .is_some_and(|mime| mime.is("multipart/form-data"));
+
+    match headers.get_list("Transfer-Encoding").as_slice() {
+        [] => {
+            let content_length = content_length(&headers)?;
+            let max_body_size = match upload {
+                true => limits.multipart.max_total_size,
+                false => limits.max_body_size as u64,
+            };
+            if content_length as u64 > max_body_size {
+                return Err(HTTPError::ContentTooLarge);
+            }
+            match upload {
+                true => Ok(Step::Streamed(header_end)),
+                false => Ok(Step::Body { end: header_end + content_length }),
+            }
+        },
+        [encoding] if encoding.eq_ignore_ascii_case("chunked") => match upload {
+            true => Ok(Step::Streamed(header_end)),
+            false => Ok(Step::ChunkSize { body_size: 0 }),
+        },
+        _ => Err(HTTPError::InvalidTransferEncoding),
+    }
+}
+
+// header lines up to and including the empty line after them, used for
+// trailers too as they look the same. None if the empty line isnt there yet,
+// count is how many lines have been seen so far
+fn scan_header_block(lines: &mut Lines, buffer: &[u8], start: usize, count: &mut usize, limits: &RequestLimits) -> Result<Option<usize>, HTTPError> {
+    loop {
+        let position = lines.position;
+        // the empty line at the end counts towards the size as well
+        let remaining = limits.max_header_size.saturating_sub(position - start);
+        let line_end = match lines.next(buffer) {
+            Some(end) if end - position > remaining => return Err(HTTPError::HeaderTooLarge),
+            Some(end) => end,
+            None if buffer.len() - position >= remaining => return Err(HTTPError::HeaderTooLarge),
+            None => return Ok(None),
+        };
+
+        let line = &buffer[position..line_end];
+        if line == b"\r\n" || line == b"\n" {
+            return Ok(Some(line_end));
+        }
+
+        *count += 1;
+        if *count > limits.max_headers {
+            return Err(HTTPError::HeaderTooLarge);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const XFF: &str = "X-Forwarded-For: 1.2.3.4\r\n";
+
+    fn scan(buffer: &str) -> Result<Progress, HTTPError> {
+        RequestScanner::default().scan(buffer.as_bytes(), &RequestLimits::default())
+    }
+
+    #[test]
+    fn requests_are_complete_once_all_of_them_is_there() {
+        let request = format!("GET / HTTP/1.1\r\n{}\r\n", XFF);
+        assert_eq!(scan("").unwrap(), Progress::Empty);
+        assert_eq!(scan("\r\n\r\n").unwrap(), Progress::Empty);
+        assert_eq!(scan("GET / HT").unwrap(), Progress::Header);
+        assert_eq!(scan(&request[..request.len() - 2]).unwrap(), Progress::Header);
+        assert_eq!(scan(&request).unwrap(), Progress::Complete(request.len()));
+        // empty lines before it count as part of it
+        assert_eq!(scan(&format!("\r\n{}", request)).unwrap(), Progress::Complete(request.len() + 2));
+    }
+
+    #[test]
+    fn pipelined_requests_are_left_for_next_time() {
+        let first = format!("POST /api/test HTTP/1.1\r\n{}Content-Length: 5\r\n\r\nhello", XFF);
+        let both = format!("{}GET / HTTP/1.1\r\n{}\r\n", first, XFF);
+        assert_eq!(scan(&first[..first.len() - 1]).unwrap(), Progress::Body);
+        assert_eq!(scan(&both).unwrap(), Progress::Complete(first.len()));
+    }
+
+    #[test]
+    fn chunked_bodies_end_after_the_trailers() {
+        let request = format!("POST / HTTP/1.1\r\n{}Transfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\nX-Sum: 3\r\n\r\n", XFF);
+        assert_eq!(scan(&request).unwrap(), Progress::Complete(request.len()));
+        assert_eq!(scan(&request[..request.len() - 2]).unwrap(), Progress::Body);
+
+        let bad_end = format!("POST / HTTP/1.1\r\n{}Transfer-Encoding: chunked\r\n\r\n3\r\nabcXY", XFF);
+        assert!(matches!(scan(&bad_end), Err(HTTPError::InvalidContent)));
+    }
+
+    #[test]
+    fn huge_chunk_sizes_cant_overflow() {
+        let request = format!("POST / HTTP/1.1\r\n{}Transfer-Encoding: chunked\r\n\r\n1\r\nX\r\nffffffffffffffff\r\n", XFF);
+        assert!(matches!(scan(&request), Err(HTTPError::ContentTooLarge)));
+
+        let request = format!("POST / HTTP/1.1\r\n{}Transfer-Encoding: chunked\r\n\r\nffffffffffffffff\r\n", XFF);
+        assert!(matches!(scan(&request), Err(HTTPError::ContentTooLarge)));
+    }
+
+    #[test]
+    fn limits_are_caught_before_the_request_is_all_there() {
+        let limits = RequestLimits {
+            max_request_line: 32,
+            max_header_size: 64,
+            max_headers: 2,
+            max_body_size: 8,
+            ..RequestLimits::default()
+        };
+        let scan = |buffer: &str| RequestScanner::default().scan(buffer.as_bytes(), &limits);
+
+        assert!(matches!(scan(&format!("GET /{}", "a".repeat(40))), Err(HTTPError::RequestLineTooLong)));
+        assert!(matches!(scan(&format!("GET / HTTP/1.1\r\nX-Big: {}", "a".repeat(70))), Err(HTTPError::HeaderTooLarge)));
+        assert!(matches!(scan("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n"), Err(HTTPError::HeaderTooLarge)));
+        assert!(matches!(scan("POST / HTTP/1.1\r\nContent-Length: 9\r\n\r\n"), Err(HTTPError::ContentTooLarge)));
+        assert!(matches!(scan("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\naaaaa\r\n4\r\n"), Err(HTTPError::ContentTooLarge)));
+    }
+
+    #[test]
+    fn uploads_are_handed_off_once_the_header_is_in() {
+        let header = format!("POST /api/mail HTTP/1.1\r\n{}Content-Type: multipart/form-data; boundary=XyZ\r\nContent-Length: 100\r\n\r\n", XFF);
+        assert_eq!(scan(&header).unwrap(), Progress::Streamed(header.len()));
+        assert_eq!(scan(&format!("{}--XyZ", header)).unwrap(), Progress::Streamed(header.len()));
+
+        let chunked = format!("POST /api/mail HTTP/1.1\r\n{}Content-Type: multipart/form-data; boundary=XyZ\r\nTransfer-Encoding: chunked\r\n\r\n", XFF);
+        assert_eq!(scan(&chunked).unwrap(), Progress::Streamed(chunked.len()));
+
+        // too big to ever be read is still caught straight away
+        let too_big = format!("POST /api/mail HTTP/1.1\r\n{}Content-Type: multipart/form-data; boundary=XyZ\r\nContent-Length: {}\r\n\r\n", XFF, u64::MAX);
+        assert!(matches!(scan(&too_big), Err(HTTPError::ContentTooLarge)));
+    }
+
+    #[test]
+    fn content_lengths_have_to_agree() {
+        let request = format!("POST / HTTP/1.1\r\n{}Content-Length: 1\r\nContent-Length: 2\r\n\r\nab", XFF);
+        assert!(matches!(scan(&request), Err(HTTPError::InvalidContentLength)));
+    }
+
+    // what each scan says when buffer comes in a byte at a time with the
+    // same scanner carrying on, next to what a fresh one says about it
+    fn scan_bytewise(buffer: &str) -> Vec<(Result<Progress, HTTPError>, Result<Progress, HTTPError>)> {
+        let limits = RequestLimits::default();
+        let mut scanner = RequestScanner::default();
+        (1..=buffer.len())
+            .map(|end| {
+                let buffer = &buffer.as_bytes()[..end];
+                (scanner.scan(buffer, &limits), RequestScanner::default().scan(buffer, &limits))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn scans_carry_on_where_they_left_off() {
+        let requests = [
+            format!("\r\nGET / HTTP/1.1\r\n{}\r\nGET /next HTTP/1.1\r\n", XFF),
+            format!("POST /api/test HTTP/1.1\r\n{}Content-Length: 5\r\n\r\nhello", XFF),
+            format!("POST / HTTP/1.1\r\n{}Transfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n10\r\n0123456789abcdef\r\n0\r\nX-Sum: 19\r\n\r\n", XFF),
+            format!("POST / HTTP/1.1\r\n{}Transfer-Encoding: chunked\r\n\r\n3\r\nabcXY", XFF),
+        ];
+        for request in &requests {
+            for (resumed, fresh) in scan_bytewise(request) {
+                match (resumed, fresh) {
+                    (Ok(resumed), Ok(fresh)) => assert_eq!(resumed, fresh),
+                    (Err(resumed), Err(fresh)) => assert_eq!(resumed.to_string(), fresh.to_string()),
+                    (resumed, fresh) => panic!("{:?} isnt {:?} for {:?}", resumed, fresh, request),
+                }
+            }
+        }
+
+        let last = scan_bytewise(&requests[2]).pop().unwrap().0;
+        assert_eq!(last.unwrap(), Progress::Complete(requests[2].len()));
+    }
+
+    #[test]
+    fn a_finished_or_failed_scan_stays_that_way() {
+        let limits = RequestLimits::default();
+        let request = format!("GET / HTTP/1.1\r\n{}\r\n", XFF);
+        let pipelined = format!("{}GET /next HTTP/1.1\r\n", request);
+        let mut scanner = RequestScanner::default();
+        assert_eq!(scanner.scan(request.as_bytes(), &limits).unwrap(), Progress::Complete(request.len()));
+        assert_eq!(scanner.scan(pipelined.as_bytes(), &limits).unwrap(), Progress::Complete(request.len()));
+
+        let broken = format!("GET / HTTP/1.1\r\n{}Content-Length: zz\r\n\r\n", XFF);
+        let mut scanner = RequestScanner::default();
+        assert!(matches!(scanner.scan(broken.as_bytes(), &limits), Err(HTTPError::InvalidContentLength)));
+        assert!(matches!(scanner.scan(format!("{}GET / HTTP/1.1\r\n", broken).as_bytes(), &limits), Err(HTTPError::InvalidContentLength)));
+    }
+}
+
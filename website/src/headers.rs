@@ -0,0 +1,118 @@
+use std::str::FromStr;
+use crate::types::HTTPError;
+
+// header names are case insensitive so 'content-type' and 'Content-Type' are
+// the same header. A header can also show up more than once (Cookie, Accept...)
+// so this keeps every line in the order it was sent instead of a HashMap
+#[derive(Debug, Clone, Default)]
+pub struct Headers {
+    fields: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Self {
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, name: &str, value: &str) {
+        self.fields.push((name.to_owned(), value.to_owned()));
+    }
+
+    // replaces every value the header already had with this one
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.add(name, value);
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.fields.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+    }
+
+    // adds an element to a list header like Vary unless its already in there
+    pub fn add_to_list(&mut self, name: &str, value: &str) {
+        if self.get_list(name).iter().any(|element| element.eq_ignore_ascii_case(value)) {
+            return;
+        }
+
+        match self.fields.iter_mut().find(|(key, _)| key.eq_ignore_ascii_case(name)) {
+            Some((_, existing)) => {
+                existing.push_str(", ");
+                existing.push_str(value);
+            },
+            None => self.add(name, value),
+        }
+    }
+
+    // gets the first value sent for the header
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // every value sent for the header in the order they came in
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.fields.iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    // for list headers like 'Accept-Encoding: gzip, br' which can be sent as
+    // one line or spread over many, this gives back each element on its own
+    pub fn get_list(&self, name: &str) -> Vec<&str> {
+        self.get_all(name)
+            .into_iter()
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .collect()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.fields.iter().any(|(key, _)| key.eq_ignore_ascii_case(name))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+// parses the raw header block of a request, one 'Name: value' per line
+impl FromStr for Headers {
+    type Err = HTTPError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut headers = Self::new();
+
+        for line in s.lines() {
+            if line.is_empty() {
+                continue;
+            }
+
+            let (name, value) = match line.split_once(':') {
+                Some(pair) => pair,
+                None => return Err(HTTPError::InvalidHeader),
+            };
+
+            // no whitespace allowed between the name and the colon
+            // and 'obs-fold' continuation lines are not worth supporting
+            if name.is_empty() || name.contains(|c: char| c.is_ascii_whitespace()) {
+                return Err(HTTPError::InvalidHeader);
+            }
+
+            headers.add(name, value.trim());
+        }
+
+        Ok(headers)
+    }
+}
@@ -1,145 +1,430 @@
 use std::collections::HashMap;
-use std::fmt::write;
-use std::net::{TcpStream, IpAddr};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::net::{IpAddr, TcpStream};
+use std::time::{SystemTime, UNIX_EPOCH, Duration};
 use std::str::FromStr;
-use std::io::{BufReader, BufRead, Read};
+use std::io::{self, BufRead, Read, Write};
+use crate::headers::Headers;
+use crate::body::Body;
+use crate::compression::{self, Encoding};
+use crate::url::{self, FormData};
+use crate::multipart::{Multipart, MultipartLimits};
+use crate::mime::Mime;
+use crate::timeout::{is_timeout, HeaderDeadline};
+use serde::{Serialize, de::DeserializeOwned};
+pub use crate::status::StatusCode;
+
+// which other sites browsers should let call us, none unless theyre listed
+#[derive(Debug, Clone)]
+pub struct CorsPolicy {
+    // whole origins like 'https://turtlebamboo.com', compared exactly
+    pub allowed_origins: Vec<String>,
+    // what a page on one of those origins can send besides the headers
+    // browsers always allow, like Accept
+    pub allowed_headers: Vec<String>,
+    // how long (in seconds) a browser can remember a preflight answer for
+    pub max_age: u64,
+}
 
-#[derive(Debug)]
-pub enum RequestType {
-    Api,
-    OtherFile,
-    Html,
+impl Default for CorsPolicy {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_headers: vec![String::from("Content-Type")],
+            max_age: 600,
+        }
+    }
 }
 
-fn make_code(code: u16) -> String {
-    match code {
-        200 => String::from("HTTP/1.1 200 OK"),
-        400 => String::from("HTTP/1.1 400 BAD REQUEST"),
-        404 => String::from("HTTP/1.1 404 NOT FOUND"),
-        405 => String::from("HTTP/1.1 405 METHOD NOT ALLOWED"),
-        415 => String::from("HTTP/1.1 415 UNSUPPORTED MEDIA TYPE"),
-        429 => String::from("HTTP/1.1 429 TOO MANY REQUESTS"),
-        500 => String::from("HTTP/1.1 500 INTERAL SERVER ERROR"),
-        _ => unimplemented!(),
+impl CorsPolicy {
+    // the requests Origin if its one thats allowed
+    pub fn allowed_origin<'a>(&self, request: &'a Request) -> Option<&'a str> {
+        let origin = request.header("Origin")?;
+        self.allowed_origins.iter()
+            .any(|allowed| allowed == origin)
+            .then_some(origin)
     }
 }
 
 #[derive(Debug)]
 pub struct Response {
-    code: u16,
+    code: StatusCode,
     content_type: ContentType,
-    modified_date: Option<SystemTime>,
+    // everything but the headers that describe how the body is sent
+    // (Content-length, Transfer-Encoding, Connection and Date) which get
+    // worked out when its written
+    headers: Headers,
     current_time: SystemTime,
-    allowed: Option<String>,
-    data: Vec<u8>,
+    keep_alive: bool,
+    version: HTTPVersion,
+    body: Body,
 }
 
 impl Response {
-    pub fn new(code: u16, content_type: ContentType, modified_date: Option<SystemTime>, allowed: Option<String>, data: Vec<u8>) -> Self {
-        let current_time = SystemTime::now();
-        Self {
-            code,
-            content_type,
-            modified_date,
-            current_time,
-            allowed,
-            data,
+    pub fn new(code: StatusCode, content_type: ContentType, modified_date: Option<SystemTime>, allowed: Option<String>, data: impl Into<Body>) -> Self {
+        let mut builder = Self::builder(code)
+            .content_type(content_type)
+            .body(data);
+        if let Some(time) = modified_date {
+            builder = builder.modified_date(time);
         }
+        if let Some(allowed) = allowed {
+            builder = builder.header("Allow", &allowed);
+        }
+        builder.build()
     }
 
-    pub fn new_ok(content_type: ContentType, modified_date: Option<SystemTime>, data: Vec<u8>) -> Self {
-        let current_time = SystemTime::now();
-        Self {
-            code: 200,
-            content_type,
-            modified_date,
-            current_time,
-            allowed: None,
-            data,
+    // for anything the other constructors dont cover
+    // Response::builder(StatusCode::SeeOther).header("Location", "/blog").build()
+    pub fn builder(code: StatusCode) -> ResponseBuilder {
+        ResponseBuilder {
+            response: Self {
+                code,
+                content_type: ContentType::PlainText,
+                headers: Headers::new(),
+                current_time: SystemTime::now(),
+                keep_alive: false,
+                version: HTTPVersion::Http11,
+                body: Body::Bytes(Vec::new()),
+            },
         }
     }
 
-    pub fn empty_404() -> Self {
-        let data = String::from("NOT FOUND").into_bytes();
-        Self {
-            code: 404,
-            content_type: ContentType::PlainText,
-            modified_date: None,
-            current_time: SystemTime::now(),
-            allowed: None,
-            data,
+    // serializes value as the body, a 500 if that somehow fails
+    pub fn json<T: Serialize>(value: &T) -> Self {
+        match serde_json::to_vec(value) {
+            Ok(data) => Self::new_ok(ContentType::Json, None, data),
+            Err(e) => {
+                println!("Error: {}\n Occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+                Self::empty_500_error()
+            },
         }
     }
 
+    pub fn new_ok(content_type: ContentType, modified_date: Option<SystemTime>, data: impl Into<Body>) -> Self {
+        Self::new(StatusCode::Ok, content_type, modified_date, None, data)
+    }
+
+    pub fn empty_404() -> Self {
+        Self::new_status(StatusCode::NotFound)
+    }
+
     pub fn empty_ok() -> Self {
-        let data = String::from("OK").into_bytes();
-        Self {
-            code: 200,
-            content_type: ContentType::PlainText,
-            modified_date: None,
-            current_time: SystemTime::now(),
-            allowed: None,
-            data,
-        }
+        Self::new_status(StatusCode::Ok)
     }
 
     pub fn empty_500_error() -> Self {
-        let data = String::from("Internal Server Error").into_bytes();
-        Self {
-            code: 500,
-            content_type: ContentType::PlainText,
-            modified_date: None,
-            current_time: SystemTime::now(),
-            allowed: None,
-            data,
-        }
+        Self::new_status(StatusCode::InternalServerError)
+    }
+
+    // a plain text response with just the reason phrase as the body, for
+    // status codes that dont need anything else like a 503 or 413
+    pub fn new_status(code: StatusCode) -> Self {
+        Self::builder(code)
+            .body(code.reason_phrase().as_bytes().to_vec())
+            .build()
+    }
+
+    // the status code depends on the error, a 400 unless it was too big
+    pub fn new_error(error: HTTPError) -> Self {
+        Self::builder(error.get_status_code())
+            .body(format!("{}", error).into_bytes())
+            .build()
     }
 
     pub fn new_400_error(error: HTTPError) -> Self {
-        let data = format!("{}", error).into_bytes();
-        Self {
-            code: 400,
-            content_type: ContentType::PlainText,
-            modified_date: None,
-            current_time: SystemTime::now(),
-            allowed: None,
-            data,
+        Self::builder(StatusCode::BadRequest)
+            .body(format!("{}", error).into_bytes())
+            .build()
+    }
+
+    // allowed is every method the resource does support, 'GET, HEAD, OPTIONS'
+    pub fn new_405_error(allowed: &str) -> Self {
+        Self::builder(StatusCode::MethodNotAllowed)
+            .header("Allow", allowed)
+            .body(StatusCode::MethodNotAllowed.reason_phrase().as_bytes().to_vec())
+            .build()
+    }
+
+    // answer to an OPTIONS request, the body is empty and the Allow header
+    // tells the client what it can do with the resource
+    pub fn new_options(allowed: &str) -> Self {
+        Self::builder(StatusCode::Ok)
+            .header("Allow", allowed)
+            .build()
+    }
+
+    // the OPTIONS a browser sends before a cross origin request, it only
+    // goes ahead if the origin is one cors allows. Anything else is just a
+    // plain OPTIONS
+    pub fn new_preflight(request: &Request, allowed: &str, cors: &CorsPolicy) -> Self {
+        let mut response = Self::new_options(allowed);
+        if !request.is_preflight() {
+            return response;
+        }
+        // the answer is different for every origin so caches have to keep them apart
+        response.add_vary("Origin");
+        let origin = match cors.allowed_origin(request) {
+            Some(origin) => origin,
+            None => return response,
+        };
+
+        response.set_header("Access-Control-Allow-Origin", origin);
+        response.set_header("Access-Control-Allow-Methods", allowed);
+        if !cors.allowed_headers.is_empty() {
+            response.set_header("Access-Control-Allow-Headers", &cors.allowed_headers.join(", "));
         }
+        response.set_header("Access-Control-Max-Age", &cors.max_age.to_string());
+        response
     }
 
-    pub fn new_405_error(accpected: &str) -> Self {
-        let data = String::from("Method Not Allowed").into_bytes();
-        Self {
-            code: 405,
-            content_type: ContentType::PlainText,
-            modified_date: None,
-            current_time: SystemTime::now(),
-            allowed: Some(accpected.into()),
-            data,
+    // sent when the client already has the newest version of the file
+    // so there is no body just the validators it can check against
+    pub fn new_not_modified(modified_date: Option<SystemTime>, etag: Option<String>) -> Self {
+        let mut builder = Self::builder(StatusCode::NotModified);
+        if let Some(time) = modified_date {
+            builder = builder.modified_date(time);
+        }
+        if let Some(etag) = etag {
+            builder = builder.etag(&etag);
         }
+        builder.build()
     }
 
-    pub fn into_bytes(self) -> Vec<u8> {
-        let header = format!("{}\r\nContent-type: {}\r\nContent-length: {}\r\n", make_code(self.code), self.content_type, self.data.len());
-        let modified_date = match self.modified_date {
-            None => String::new(),
-            Some(time) => format!("Last-Modified: {}\r\n", turn_system_time_to_http_date(time)),
-        };
+    // only part of the file was asked for, content_range is the 'bytes 0-499/1234'
+    // that tells the client which part of it this is. multipart/byteranges bodies
+    // dont have one as each part carries its own
+    pub fn new_partial(content_type: ContentType, modified_date: Option<SystemTime>, content_range: Option<String>, data: impl Into<Body>) -> Self {
+        let mut response = Self::new(StatusCode::PartialContent, content_type, modified_date, None, data);
+        if let Some(range) = content_range {
+            response.set_header("Content-Range", &range);
+        }
+        response.set_accept_ranges(true);
+        response
+    }
+
+    // none of the asked for ranges are inside of the file
+    pub fn new_range_not_satisfiable(length: u64) -> Self {
+        let mut response = Self::new_status(StatusCode::RangeNotSatisfiable);
+        response.set_header("Content-Range", &format!("bytes */{}", length));
+        response.set_accept_ranges(true);
+        response
+    }
+
+    pub fn get_code(&self) -> StatusCode {
+        self.code
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    // adds another line for the header, for ones that can be sent more than
+    // once like Set-Cookie
+    pub fn add_header(&mut self, name: &str, value: &str) {
+        if is_valid_header(name, value) {
+            self.headers.add(name, value);
+        }
+    }
+
+    // replaces whatever the header was set to before
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        if is_valid_header(name, value) {
+            self.headers.set(name, value);
+        }
+    }
+
+    pub fn remove_header(&mut self, name: &str) {
+        self.headers.remove(name);
+    }
+
+    // the response would have been different if the client sent a different
+    // value for this header, like Accept or Accept-Encoding
+    pub fn add_vary(&mut self, header: &str) {
+        self.headers.add_to_list("Vary", header);
+    }
 
-        let accpected = match self.allowed {
-            None => String::new(),
-            Some(s) => format!("Accpect: {}\r\n", s),
+    // lets clients know they can ask for part of this with a Range header
+    pub fn set_accept_ranges(&mut self, accept_ranges: bool) {
+        match accept_ranges {
+            true => self.headers.set("Accept-Ranges", "bytes"),
+            false => self.headers.remove("Accept-Ranges"),
+        }
+    }
+
+    // the body is already encoded, like a precompressed file read off disk
+    pub fn set_content_encoding(&mut self, encoding: Encoding) {
+        if encoding != Encoding::Identity {
+            self.headers.set("Content-Encoding", &encoding.to_string());
+        }
+        self.headers.add_to_list("Vary", "Accept-Encoding");
+    }
+
+    // compresses the body if its worth it, only plain buffers get compressed
+    // here as files already had their chance when they were read
+    pub fn compress(&mut self, encoding: Encoding) {
+        if !self.content_type.is_compressible() {
+            return;
+        }
+        // whether or not it gets compressed this time the response could
+        // have been different for a different Accept-Encoding
+        self.headers.add_to_list("Vary", "Accept-Encoding");
+
+        if self.code != StatusCode::Ok || encoding == Encoding::Identity || self.headers.contains("Content-Encoding") {
+            return;
+        }
+
+        let data = match &self.body {
+            Body::Bytes(data) if data.len() >= compression::MIN_COMPRESS_SIZE => data,
+            _ => return,
         };
 
-        let date = format!("Date: {}\r\n\r\n", turn_system_time_to_http_date(self.current_time));
+        match compression::compress(data, encoding) {
+            Ok(compressed) => {
+                self.body = Body::Bytes(compressed);
+                self.headers.set("Content-Encoding", &encoding.to_string());
+                if let Some(etag) = self.headers.get("ETag") {
+                    let etag = compression::encoded_etag(etag, encoding);
+                    self.headers.set("ETag", &etag);
+                }
+            },
+            Err(e) => println!("Error: {}\n Occured at: {}", e, turn_system_time_to_http_date(SystemTime::now())),
+        }
+    }
+
+    pub fn set_etag(&mut self, etag: &str) {
+        self.set_header("ETag", etag);
+    }
+
+    // whether the connection stays open after this response is sent
+    pub fn set_keep_alive(&mut self, keep_alive: bool) {
+        self.keep_alive = keep_alive;
+    }
+
+    // the version the request came in as, HTTP/1.0 clients cant be sent
+    // a chunked body so they get one that ends when the connection does
+    pub fn set_version(&mut self, version: HTTPVersion) {
+        self.version = version;
+    }
+
+    fn is_close_delimited(&self) -> bool {
+        self.body.len().is_none() && self.version == HTTPVersion::Http10
+    }
+
+    // a body that ends when the connection closes cant be followed by anything
+    pub fn will_keep_alive(&self) -> bool {
+        self.keep_alive && !self.is_close_delimited()
+    }
+
+    pub fn write_to(self, stream: &mut TcpStream) -> Result<(), std::io::Error> {
+        if !self.code.allows_body() {
+            return self.write_head_to(stream);
+        }
+        let header = self.header_string();
+        let chunked = !self.is_close_delimited();
+        self.body.write_to(stream, header.as_bytes(), chunked)
+    }
+
+    // used for HEAD requests, same headers as the GET would have gotten
+    // (including the Content-length) but without the body
+    pub fn write_head_to(self, stream: &mut TcpStream) -> Result<(), std::io::Error> {
+        stream.write_all(self.header_string().as_bytes())
+    }
+
+    // bodies go out in a seperate write from the header so dont let nagle
+    // hold back the last little bit of a file waiting for an ack, for every
+    // connection before anything gets written to it
+    pub fn prepare_stream(stream: &TcpStream) -> Result<(), std::io::Error> {
+        stream.set_nodelay(true)
+    }
+
+    fn header_string(&self) -> String {
+        // 1xx, 204 and 304 have no body so they shouldnt say anything about one
+        let mut line = format!("HTTP/1.1 {}\r\n", self.code);
+        match self.body.len() {
+            _ if !self.code.allows_body() => {},
+            Some(length) => line += &format!("Content-type: {}\r\nContent-length: {}\r\n", self.content_type.header_value(), length),
+            None if self.is_close_delimited() => line += &format!("Content-type: {}\r\n", self.content_type.header_value()),
+            None => line += &format!("Content-type: {}\r\nTransfer-Encoding: chunked\r\n", self.content_type.header_value()),
+        }
+
+        for (name, value) in self.headers.iter() {
+            line += &format!("{}: {}\r\n", name, value);
+        }
+
+        match self.will_keep_alive() {
+            true => line += "Connection: keep-alive\r\n",
+            false => line += "Connection: close\r\n",
+        }
 
-        let line = header + &modified_date + &accpected + &date;
+        line += &format!("Date: {}\r\n\r\n", turn_system_time_to_http_date(self.current_time));
         println!("{}", line);
-        [line.as_bytes(), &self.data].concat()
+        line
     }
 }
 
+// Response::builder(StatusCode::Ok)
+//     .content_type(ContentType::Html)
+//     .header("Cache-Control", "max-age=3600")
+//     .body(data)
+//     .build()
+pub struct ResponseBuilder {
+    response: Response,
+}
+
+impl ResponseBuilder {
+    pub fn content_type(mut self, content_type: ContentType) -> Self {
+        self.response.content_type = content_type;
+        self
+    }
+
+    pub fn modified_date(mut self, time: SystemTime) -> Self {
+        self.response.set_header("Last-Modified", &turn_system_time_to_http_date(time));
+        self
+    }
+
+    pub fn etag(mut self, etag: &str) -> Self {
+        self.response.set_etag(etag);
+        self
+    }
+
+    // adds the header without touching any it was already given so headers
+    // like Set-Cookie can be added more than once
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.response.add_header(name, value);
+        self
+    }
+
+    pub fn body(mut self, data: impl Into<Body>) -> Self {
+        self.response.body = data.into();
+        self
+    }
+
+    pub fn build(self) -> Response {
+        self.response
+    }
+}
+
+// these are always worked out from the response itself so they cant be set by
+// hand (Content-Type has its own setter), and a new line in a value would let
+// it sneak in headers of its own
+fn is_valid_header(name: &str, value: &str) -> bool {
+    const FRAMING_HEADERS: [&str; 5] = ["Content-Type", "Content-Length", "Transfer-Encoding", "Connection", "Date"];
+
+    let valid_name = !name.is_empty() && name.bytes().all(|b| b.is_ascii_graphic() && b != b':');
+    let valid_value = !value.contains(['\r', '\n']);
+    let framing = FRAMING_HEADERS.iter().any(|header| header.eq_ignore_ascii_case(name));
+
+    if !valid_name || !valid_value || framing {
+        println!("Refusing to set header: {:?}: {:?}", name, value);
+        return false;
+    }
+    true
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum ContentType {
     Image(ImageType),
@@ -149,10 +434,23 @@ pub enum ContentType {
     Html,
     PlainText,
     OctetStream, // should be raw binary
+    FormUrlEncoded,
+    Json,
+    MultipartFormData,
     Wasm,
     Wgsl,
+    Markdown,
+    // used for sending more than one range of a file at once
+    MultipartByteRanges,
+    // anything without its own variant, like 'video/mp4'
+    Other(&'static str),
+    // a request sent a type none of these are, get_mime still has what it was
+    Unknown,
 }
 
+// seperates each range in a multipart/byteranges body
+pub const BYTERANGES_BOUNDARY: &str = "BOTTOMLESS_SITE_BYTERANGES";
+
 #[derive(Clone, Copy, Debug)]
 pub enum FontType {
     Collection,
@@ -168,21 +466,70 @@ pub enum ImageType {
     Png,
     Svg,
     XIcon,
+    Jpeg,
+    Gif,
+    Webp,
+    Avif,
+}
+
+impl ContentType {
+    // text based types shrink a lot when compressed, images and fonts
+    // are usually already compressed so its just wasted work
+    // text/* types get sent with '; charset=utf-8' so browsers dont guess
+    pub fn is_text(&self) -> bool {
+        match self {
+            Self::Html | Self::Css | Self::JavaScript | Self::PlainText | Self::Wgsl | Self::Markdown => true,
+            Self::Other(name) => name.starts_with("text/"),
+            _ => false,
+        }
+    }
+
+    // what goes in the Content-Type header of a response
+    pub fn header_value(&self) -> String {
+        match self.is_text() {
+            true => format!("{}; charset=utf-8", self),
+            false => self.to_string(),
+        }
+    }
+
+    pub fn is_compressible(&self) -> bool {
+        match self {
+            Self::Html | Self::Css | Self::JavaScript | Self::Wasm | Self::Wgsl | Self::Image(ImageType::Svg)
+                | Self::PlainText | Self::Json | Self::Markdown => true,
+            Self::Other(name) => name.starts_with("text/") || name.ends_with("json") || name.ends_with("xml"),
+            _ => false,
+        }
+    }
 }
 
 impl std::str::FromStr for ContentType {
     type Err = HTTPError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         println!("{s}");
-        match s {
+        Self::from_mime(&Mime::from_str(s)?)
+    }
+}
+
+impl ContentType {
+    // parameters like '; boundary=' or '; charset=' dont change the type
+    pub fn from_mime(mime: &Mime) -> Result<Self, HTTPError> {
+        match mime.essence().as_str() {
             "image/png" => Ok(Self::Image(ImageType::Png)),
             "image/svg+xml" => Ok(Self::Image(ImageType::Svg)),
             "image/x-icon" => Ok(Self::Image(ImageType::XIcon)),
+            "image/jpeg" => Ok(Self::Image(ImageType::Jpeg)),
+            "image/gif" => Ok(Self::Image(ImageType::Gif)),
+            "image/webp" => Ok(Self::Image(ImageType::Webp)),
+            "image/avif" => Ok(Self::Image(ImageType::Avif)),
+            "text/markdown" => Ok(Self::Markdown),
             "text/css" => Ok(Self::Css),
             "text/javascript" => Ok(Self::JavaScript),
             "text/html" => Ok(Self::Html),
             "text/plain" => Ok(Self::PlainText),
             "application/octet-stream" => Ok(Self::OctetStream),
+            "application/x-www-form-urlencoded" => Ok(Self::FormUrlEncoded),
+            "application/json" => Ok(Self::Json),
+            "multipart/form-data" => Ok(Self::MultipartFormData),
             "application/wasm" => Ok(Self::Wasm),
             "text/wgsl" => Ok(Self::Wgsl),
             _ => Err(HTTPError::InvalidContentType),
@@ -196,6 +543,10 @@ impl std::fmt::Display for ContentType {
             Self::Image(ImageType::Png) => write!(f, "image/png"),
             Self::Image(ImageType::Svg) => write!(f, "image/svg+xml"),
             Self::Image(ImageType::XIcon) => write!(f, "image/x-icon"),
+            Self::Image(ImageType::Jpeg) => write!(f, "image/jpeg"),
+            Self::Image(ImageType::Gif) => write!(f, "image/gif"),
+            Self::Image(ImageType::Webp) => write!(f, "image/webp"),
+            Self::Image(ImageType::Avif) => write!(f, "image/avif"),
             Self::Font(FontType::Collection) => write!(f, "font/collection"),
             Self::Font(FontType::Otf) => write!(f, "font/otf"),
             Self::Font(FontType::Sfnt) => write!(f, "font/sfnt"),
@@ -207,145 +558,470 @@ impl std::fmt::Display for ContentType {
             Self::Html => write!(f, "text/html"),
             Self::PlainText => write!(f, "text/plain"),
             Self::OctetStream => write!(f, "application/octet-stream"),
+            Self::FormUrlEncoded => write!(f, "application/x-www-form-urlencoded"),
+            Self::Json => write!(f, "application/json"),
+            Self::MultipartFormData => write!(f, "multipart/form-data"),
             Self::Wasm => write!(f, "application/wasm"),
             Self::Wgsl => write!(f, "text/wgsl"),
+            Self::Markdown => write!(f, "text/markdown"),
+            Self::MultipartByteRanges => write!(f, "multipart/byteranges; boundary={}", BYTERANGES_BOUNDARY),
+            Self::Other(name) => write!(f, "{}", name),
+            // nothing more is known about it than that its bytes
+            Self::Unknown => write!(f, "application/octet-stream"),
         }
     }
 }
 
-// used for API's to take a request either get or post without wierd jank,
-#[derive(Debug)]
-pub enum Request {
-    GetRequest(GETRequest),
-    POSTRequest(POSTRequest),
+// how big a request is allowed to be before its turned away, without these
+// a client could send an endless header or claim a body of a few gigabytes
+// and have us try to hold all of it in memory
+#[derive(Debug, Clone)]
+pub struct RequestLimits {
+    // 'GET /path?query HTTP/1.1' including the new line, over this is a 414
+    pub max_request_line: usize,
+    // all of the header lines together, over this is a 431
+    pub max_header_size: usize,
+    // how many header lines there can be, over this is a 431 too
+    pub max_headers: usize,
+    // the body after any chunked encoding is undone, over this is a 413
+    pub max_body_size: usize,
+    // multipart/form-data bodies are parsed as they come in instead of
+    // being read into memory so they go by these instead of max_body_size
+    pub multipart: MultipartLimits,
 }
 
-impl Request {
-    pub fn new(stream: &mut TcpStream) -> Result<Self, HTTPError> {
-        let mut buf_reader = BufReader::new(stream);
-
-        // should theoretically grab the 'GET path HTTP/1.1\r\n' 
-        let mut first_line_buffer = Vec::new();
-        let request_line_string = match buf_reader.read_until(b'\n', &mut first_line_buffer) {
-            Ok(_) => {
-                match String::from_utf8(first_line_buffer) {
-                    Ok(string) => string,
-                    Err(e) => {
-                        println!("Error: {}\n Occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
-                        return Err(HTTPError::InvalidRequestLine);
-                    },
-                }
-            },
-            Err(e) => {
-                println!("Error: {}\n Occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
-                return Err(HTTPError::InvalidRequestLine);
-            }
-        };
-
-        let request_line = HTTPRequestLine::from_str(&request_line_string)?;
-
-        match request_line.get_kind() {
-            HTTPType::Get => Ok(Self::GetRequest(GETRequest::new(request_line, buf_reader)?)),
-            HTTPType::Post => Ok(Self::POSTRequest(POSTRequest::new(request_line, buf_reader)?)),
-        }
-    }
-
-    pub fn get_path(&self) -> &str {
-        match self {
-            Request::GetRequest(r) => &r.path,
-            Request::POSTRequest(r) => &r.path,
-        }
-    }
-
-    pub fn get_ip(&self) -> IpAddr {
-        match self {
-            Request::GetRequest(r) => r.ip,
-            Request::POSTRequest(r) => r.ip,
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            max_request_line: 8 * 1024,
+            max_header_size: 16 * 1024,
+            max_headers: 100,
+            max_body_size: 10 * 1024 * 1024,
+            multipart: MultipartLimits::default(),
         }
     }
 }
 
+// used for API's to take a request of any method without wierd jank,
+// every method carries the same query, body and ip so handlers only have to
+// look at the kind when they care about it
 #[derive(Debug)]
-pub struct POSTRequest {
+pub struct Request {
+    kind: HTTPType,
+    version: HTTPVersion,
     path: String,
-    query_string: HashMap<String, String>,
-    host: String,
+    query_string: FormData,
+    headers: Headers,
     ip: IpAddr,
     content_type: ContentType,
+    // the full Content-Type with its parameters like charset and boundary
+    mime: Option<Mime>,
     content_length: usize,
     content: Vec<u8>,
+    // the parts of a multipart/form-data body, which never goes in content
+    multipart: Option<Multipart>,
+    // extra headers sent after a chunked body
+    trailers: Headers,
+    // the :name parts of the api route this request matched
+    params: HashMap<String, String>,
 }
 
-impl POSTRequest {
-    pub fn new(line: HTTPRequestLine, reader: BufReader<&mut TcpStream>) -> Result<Self, HTTPError>{
-        let (path, query_string) = match line.path.split_once("?") {
-            Some((left, right)) => {
-                let queries = process_query_string(right)?;
-                (left.to_owned(), queries)
-            },
-            None => (line.path, HashMap::new())
+impl Request {
+    // takes the reader instead of the stream so the same buffer can be used for
+    // every request on a keep-alive connection without losing pipelined bytes
+    pub fn new<R: BufRead + HeaderDeadline>(reader: &mut R, limits: &RequestLimits) -> Result<Self, HTTPError> {
+        // should theoretically grab the 'GET path HTTP/1.1\r\n' 
+        // clients are allowed to send some empty lines before it though
+        let request_line_string = loop {
+            let mut first_line_buffer = Vec::new();
+            match reader.by_ref().take(limits.max_request_line as u64).read_until(b'\n', &mut first_line_buffer) {
+                // the client hung up between requests, nothing went wrong
+                Ok(0) => return Err(HTTPError::ConnectionClosed),
+                Ok(_) if line_too_long(&first_line_buffer, limits.max_request_line) => {
+                    return Err(HTTPError::RequestLineTooLong);
+                },
+                Ok(_) => {},
+                Err(e) if first_line_buffer.is_empty() && is_timeout(&e) => {
+                    // idle keep-alive connection ran out of time
+                    return Err(HTTPError::ConnectionClosed);
+                },
+                // started sending a request but then stalled
+                Err(e) if is_timeout(&e) => return Err(HTTPError::RequestTimeout),
+                Err(e) => {
+                    println!("Error: {}\n Occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+                    return Err(HTTPError::InvalidRequestLine);
+                }
+            }
+
+            match String::from_utf8(first_line_buffer) {
+                Ok(string) if string.trim().is_empty() => continue,
+                Ok(string) => break string,
+                Err(e) => {
+                    println!("Error: {}\n Occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+                    return Err(HTTPError::InvalidRequestLine);
+                },
+            }
         };
 
+        let request_line = HTTPRequestLine::from_str(&request_line_string)?;
+        let kind = request_line.get_kind();
+        let version = request_line.get_version();
 
-        let (header, mut reader) = split_header(reader)?;
+        let query_string = match &request_line.query {
+            Some(query) => FormData::parse(query).ok_or(HTTPError::InvalidPath)?,
+            None => FormData::new(),
+        };
+        let path = request_line.path;
 
-        let mut host = String::new();
-        let mut content_type = ContentType::PlainText;
-        let mut content_length = 0;
-        let mut ip_str = "";
+        let header = split_header(reader, limits)?;
+        // the body gets its own deadline from here on
+        reader.header_received();
 
-        for line in header.lines() {
-            if line.starts_with("Content-Type: ") {
-                content_type = ContentType::from_str(&line[14..])?;
-            } else if line.starts_with("Host: ") {
-                host.push_str(&line[6..]);
-            } else if line.starts_with("Content-Length: ") {
-                content_length = match line[16..].parse() {
-                    Err(_) => return Err(HTTPError::InvalidContentLength),
-                    Ok(num) => num,
-                };
-            } else if line.starts_with("X-Forwarded-For: ") {
-                ip_str = &line[17..];
-            }
+        let headers = Headers::from_str(&header)?;
+
+        let mime = match headers.get("Content-Type") {
+            None => None,
+            Some(value) => Some(Mime::from_str(value)?),
+        };
+        // a valid type thats just not one we know isnt wrong, its up to the
+        // handler whether it can take it (and answer 415 if not)
+        let content_type = match &mime {
+            None => ContentType::PlainText,
+            Some(mime) => ContentType::from_mime(mime).unwrap_or(ContentType::Unknown),
+        };
+
+        let content_length = content_length(&headers)?;
+        let max_body_size = match content_type {
+            ContentType::MultipartFormData => limits.multipart.max_total_size,
+            _ => limits.max_body_size as u64,
+        };
+        // checked before reading anything so the body never gets buffered
+        if content_length as u64 > max_body_size {
+            return Err(HTTPError::ContentTooLarge);
         }
 
+        // when behind more than one proxy this is 'client, proxy1, proxy2'
+        let ip_str = headers.get_list("X-Forwarded-For")
+            .first()
+            .copied()
+            .unwrap_or_default();
+
         let ip = match IpAddr::from_str(ip_str) {
             Ok(ip) => ip,
             Err(_) => Err(HTTPError::FailedToObtainIP)?,
         };
 
-        // read content length
-        let mut content: Vec<u8> = Vec::with_capacity(content_length);
-        let mut amount_read = 0;
-        while amount_read < content_length {
-            const BUFFER_SIZE: usize = 10;
-            let mut buffer = [0_u8; BUFFER_SIZE];
-            let amount_to_read = BUFFER_SIZE.min(content_length - amount_read);
-            match reader.read_exact(&mut buffer[..amount_to_read]) {
-                Err(_) => return Err(HTTPError::InvalidContent),
-                Ok(_) => {},
-            }
-            content.extend(&buffer[..amount_to_read]);
-            amount_read += BUFFER_SIZE;
-        }
+        // chunked bodies say how long each piece is as they go instead of upfront
+        let transfer_encoding = headers.get_list("Transfer-Encoding");
+        let chunked = match transfer_encoding.as_slice() {
+            [] => false,
+            // having both is how request smuggling works so its not allowed
+            _ if headers.contains("Content-Length") => return Err(HTTPError::InvalidTransferEncoding),
+            // HTTP/1.0 doesnt know what chunked is
+            _ if version == HTTPVersion::Http10 => return Err(HTTPError::InvalidTransferEncoding),
+            [encoding] if encoding.eq_ignore_ascii_case("chunked") => true,
+            // gzip and friends on request bodies arent supported
+            _ => return Err(HTTPError::InvalidTransferEncoding),
+        };
+
+        let boundary = match content_type {
+            ContentType::MultipartFormData => match mime.as_ref().and_then(|mime| mime.get_param("boundary")) {
+                Some(boundary) => Some(boundary),
+                None => return Err(HTTPError::InvalidContentType),
+            },
+            _ => None,
+        };
+
+        let (content, multipart, trailers, content_length) = match (boundary, chunked) {
+            (None, false) => {
+                let content = read_body(reader, content_length)?;
+                (content, None, Headers::new(), content_length)
+            },
+            (None, true) => {
+                let (content, trailers) = read_chunked_body(reader, limits)?;
+                let content_length = content.len();
+                (content, None, trailers, content_length)
+            },
+            (Some(boundary), false) => {
+                let mut body = reader.by_ref().take(content_length as u64);
+                let multipart = read_upload(&mut body, boundary, &limits.multipart)?;
+                // the client hung up before sending all of what it said it would
+                if body.limit() > 0 {
+                    return Err(HTTPError::InvalidContent);
+                }
+                (Vec::new(), Some(multipart), Headers::new(), content_length)
+            },
+            (Some(boundary), true) => {
+                let mut chunks = ChunkedReader::new(reader, limits.multipart.max_total_size);
+                let multipart = match read_upload(&mut chunks, boundary, &limits.multipart) {
+                    Ok(multipart) => multipart,
+                    // a broken or too big chunk looks like the body ending early to the parser
+                    Err(e) => return Err(chunks.error.unwrap_or(e)),
+                };
+                let content_length = chunks.body_size as usize;
+                let trailers = chunks.trailers(limits)?;
+                (Vec::new(), Some(multipart), trailers, content_length)
+            },
+        };
 
         Ok(Self {
+            kind,
+            version,
             path,
-            host,
             query_string,
+            headers,
             ip,
             content_type,
+            mime,
             content_length,
-            content
+            content,
+            multipart,
+            trailers,
+            params: HashMap::new(),
         })
     }
 
+    pub fn get_kind(&self) -> HTTPType {
+        self.kind
+    }
+
+    pub fn get_version(&self) -> HTTPVersion {
+        self.version
+    }
+
+    // a CORS preflight rather than someone asking what methods there are
+    pub fn is_preflight(&self) -> bool {
+        self.kind == HTTPType::Options
+            && self.headers.contains("Origin")
+            && self.headers.contains("Access-Control-Request-Method")
+    }
+
+    // HTTP/1.1 keeps the connection open unless told otherwise
+    // HTTP/1.0 closes it unless the client asks for keep-alive
+    pub fn wants_keep_alive(&self) -> bool {
+        let connection = self.headers.get_list("Connection");
+        let has_token = |token: &str| connection.iter().any(|value| value.eq_ignore_ascii_case(token));
+
+        match self.version {
+            HTTPVersion::Http11 => !has_token("close"),
+            HTTPVersion::Http10 => has_token("keep-alive"),
+        }
+    }
+
+    // checks If-None-Match and If-Modified-Since against what we currently have
+    // true means the client's copy is still good and a 304 can be sent instead
+    pub fn is_not_modified(&self, etag: Option<&str>, last_modified: Option<SystemTime>) -> bool {
+        // only a GET or HEAD can be answered with a 304
+        if self.kind != HTTPType::Get && self.kind != HTTPType::Head {
+            return false;
+        }
+
+        // If-None-Match wins when both are sent as its more precise than a date
+        if self.headers.contains("If-None-Match") {
+            let etag = match etag {
+                None => return false,
+                Some(etag) => etag,
+            };
+
+            return self.headers.get_list("If-None-Match")
+                .into_iter()
+                .any(|tag| tag == "*" || weak_etag_compare(tag, etag));
+        }
+
+        let since = match self.headers.get("If-Modified-Since").and_then(parse_http_date) {
+            None => return false,
+            Some(since) => since,
+        };
+
+        match last_modified {
+            None => false,
+            // http dates only go down to the second so anything smaller gets dropped
+            Some(modified) => match modified.duration_since(UNIX_EPOCH) {
+                Ok(modified) => UNIX_EPOCH + Duration::from_secs(modified.as_secs()) <= since,
+                Err(_) => false,
+            },
+        }
+    }
+
+    // which of the available encodings the client would like the most
+    pub fn preferred_encoding(&self, available: &[Encoding]) -> Encoding {
+        compression::negotiate(&self.headers.get_list("Accept-Encoding"), available)
+    }
+
+    // picks the type out of available the client wants the most based on its
+    // Accept header, 'application/json, */*;q=0.1'. No Accept header means
+    // anything goes so the first one is used, None if nothing is acceptable
+    pub fn preferred_content_type(&self, available: &[ContentType]) -> Option<ContentType> {
+        let accept = self.headers.get_list("Accept");
+        if accept.is_empty() {
+            return available.first().copied();
+        }
+
+        let mut best = None;
+        let mut best_quality = 0.0;
+        for content_type in available {
+            let name = content_type.to_string();
+            let essence = name.split(';').next().unwrap_or("").trim();
+            let (main_type, _) = essence.split_once('/').unwrap_or((essence, ""));
+
+            // the most specific range that matches is the one that counts
+            let quality = accept.iter()
+                .filter_map(|element| {
+                    let mut parts = element.split(';').map(str::trim);
+                    let range = parts.next()?;
+                    let specificity = match range {
+                        "*/*" => 0,
+                        range if range.eq_ignore_ascii_case(essence) => 2,
+                        range => match range.strip_suffix("/*") {
+                            Some(range_type) if range_type.eq_ignore_ascii_case(main_type) => 1,
+                            _ => return None,
+                        },
+                    };
+                    let quality = parts
+                        .find_map(|param| param.strip_prefix("q=").or_else(|| param.strip_prefix("Q=")))
+                        .map(|q| q.parse::<f32>().unwrap_or(0.0))
+                        .unwrap_or(1.0);
+                    Some((specificity, quality))
+                })
+                .max_by_key(|(specificity, _)| *specificity)
+                .map(|(_, quality)| quality)
+                .unwrap_or(0.0);
+
+            // strictly greater so ties go to whatever came first in available
+            if quality > best_quality {
+                best = Some(*content_type);
+                best_quality = quality;
+            }
+        }
+
+        best
+    }
+
+    // the parts of a multipart/form-data body. Its parsed as the body comes
+    // in so by now any part over the limits memory_threshold is in a temp
+    // file, which gets deleted along with the request
+    pub fn get_multipart(&self) -> Result<&Multipart, HTTPError> {
+        self.multipart.as_ref().ok_or(HTTPError::InvalidContentType)
+    }
+
+    // parses a json body into whatever type the api wants
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, HTTPError> {
+        if !matches!(self.content_type, ContentType::Json) {
+            return Err(HTTPError::InvalidContentType);
+        }
+
+        match serde_json::from_slice(&self.content) {
+            Ok(value) => Ok(value),
+            Err(e) => {
+                println!("Invalid json: {}", e);
+                Err(HTTPError::InvalidContent)
+            },
+        }
+    }
+
+    // If-Range makes the Range only count if the file hasnt changed since the
+    // client got the first part of it, otherwise they need the whole thing again
+    pub fn if_range_matches(&self, etag: Option<&str>, last_modified: Option<SystemTime>) -> bool {
+        let if_range = match self.headers.get("If-Range") {
+            None => return true,
+            Some(value) => value,
+        };
+
+        // a weak tag can never be used for a range as the bytes might be different
+        if if_range.starts_with('"') || if_range.starts_with("W/") {
+            return match etag {
+                Some(etag) => !if_range.starts_with("W/") && !etag.starts_with("W/") && if_range == etag,
+                None => false,
+            };
+        }
+
+        match (parse_http_date(if_range), last_modified) {
+            (Some(date), Some(modified)) => match modified.duration_since(UNIX_EPOCH) {
+                Ok(modified) => UNIX_EPOCH + Duration::from_secs(modified.as_secs()) == date,
+                Err(_) => false,
+            },
+            _ => false,
+        }
+    }
+
+    // works out which parts of a file that is length bytes long the client wants
+    // anything we dont understand means they just get the whole file
+    pub fn get_byte_ranges(&self, length: u64) -> ByteRanges {
+        // only a GET can ask for part of something
+        if self.kind != HTTPType::Get {
+            return ByteRanges::Full;
+        }
+
+        let ranges = match self.headers.get("Range").and_then(|range| range.strip_prefix("bytes=")) {
+            None => return ByteRanges::Full,
+            Some(ranges) => ranges,
+        };
+
+        let mut satisfiable = Vec::new();
+        let mut count = 0;
+        for range in ranges.split(',').map(str::trim).filter(|range| !range.is_empty()) {
+            count += 1;
+            // asking for a ton of tiny ranges is a good way to make us do a lot
+            // of work for nothing so its just ignored
+            if count > MAX_RANGES {
+                return ByteRanges::Full;
+            }
+
+            let (start, end) = match range.split_once('-') {
+                None => return ByteRanges::Full,
+                Some(pair) => pair,
+            };
+
+            let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+                // bytes=500-999
+                (Ok(start), Ok(end)) if start <= end => Some((start, end.min(length.saturating_sub(1)))),
+                // bytes=9500-
+                (Ok(start), Err(_)) if end.is_empty() => Some((start, length.saturating_sub(1))),
+                // bytes=-500 which is the last 500 bytes
+                (Err(_), Ok(suffix)) if start.is_empty() => match suffix {
+                    0 => None,
+                    suffix => Some((length.saturating_sub(suffix), length.saturating_sub(1))),
+                },
+                _ => return ByteRanges::Full,
+            };
+
+            if let Some((start, end)) = range {
+                if start < length {
+                    satisfiable.push((start, end));
+                }
+            }
+        }
+
+        match satisfiable.is_empty() {
+            true if count == 0 => ByteRanges::Full,
+            true => ByteRanges::Unsatisfiable,
+            false => ByteRanges::Partial(satisfiable),
+        }
+    }
+
     pub fn get_path(&self) -> &str {
         &self.path
     }
 
+    pub fn get_ip(&self) -> IpAddr {
+        self.ip
+    }
+
     pub fn get_host(&self) -> &str {
-        &self.host
+        self.headers.get("Host").unwrap_or_default()
+    }
+
+    // first value of a header, the name is case insensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    pub fn trailers(&self) -> &Headers {
+        &self.trailers
     }
 
     pub fn get_data(&self) -> &[u8] {
@@ -360,46 +1036,249 @@ impl POSTRequest {
         self.content_type
     }
 
-    pub fn get_query(&self, key: &str) -> Option<&String> {
+    pub fn get_mime(&self) -> Option<&Mime> {
+        self.mime.as_ref()
+    }
+
+    // the first value for the key, already decoded so '?q=C%2B%2B+%26+Rust'
+    // gives back 'C++ & Rust'. A key sent without a value ('?debug') is ""
+    pub fn get_query(&self, key: &str) -> Option<&str> {
         self.query_string.get(key)
     }
+
+    // for keys sent more than once, '?tag=a&tag=b'
+    pub fn get_query_all(&self, key: &str) -> Vec<&str> {
+        self.query_string.get_all(key)
+    }
+
+    pub fn get_queries(&self) -> &FormData {
+        &self.query_string
+    }
+
+    // the body of a form POST, parsed the same way as the query string
+    pub fn get_form(&self) -> Result<FormData, HTTPError> {
+        if !matches!(self.content_type, ContentType::FormUrlEncoded) {
+            return Err(HTTPError::InvalidContentType);
+        }
+
+        let body = match std::str::from_utf8(&self.content) {
+            Ok(body) => body,
+            Err(_) => return Err(HTTPError::InvalidContent),
+        };
+        FormData::parse(body).ok_or(HTTPError::InvalidContent)
+    }
+
+    // '42' for '/api/blog/42' when the api was registered as '/api/blog/:id'
+    pub fn get_param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(String::as_str)
+    }
+
+    pub fn get_params(&self) -> &HashMap<String, String> {
+        &self.params
+    }
+
+    pub fn set_params(&mut self, params: HashMap<String, String>) {
+        self.params = params;
+    }
+}
+
+// read content length, a GET or HEAD will usually just have 0 here. The
+// length was already checked against the limit but the buffer still grows
+// as data comes in instead of trusting it upfront
+fn read_body<R: BufRead>(reader: &mut R, content_length: usize) -> Result<Vec<u8>, HTTPError> {
+    let mut content = Vec::new();
+    match reader.by_ref().take(content_length as u64).read_to_end(&mut content) {
+        Ok(read) if read == content_length => Ok(content),
+        Ok(_) => Err(HTTPError::InvalidContent),
+        Err(e) => Err(read_error(e, HTTPError::InvalidContent)),
+    }
+}
+
+// a chunk size line is a hex number and maybe some extensions, nothing
+// real comes close to this
+pub(crate) const MAX_CHUNK_SIZE_LINE: usize = 1024;
+
+// a chunked body looks like:
+// 1a;optional=extension\r\n
+// 26 bytes of data\r\n
+// 0\r\n
+// Optional-Trailer: value\r\n
+// \r\n
+// a request with more than one differing Content-Length is either broken
+// or someone trying to smuggle a second request past us, none means no body
+pub(crate) fn content_length(headers: &Headers) -> Result<usize, HTTPError> {
+    let mut content_length = None;
+    for value in headers.get_all("Content-Length") {
+        let length: usize = match value.parse() {
+            Err(_) => return Err(HTTPError::InvalidContentLength),
+            Ok(num) => num,
+        };
+        match content_length {
+            Some(previous) if previous != length => return Err(HTTPError::InvalidContentLength),
+            _ => content_length = Some(length),
+        }
+    }
+    Ok(content_length.unwrap_or(0))
+}
+
+// the size line before each chunk, body_size is how much of the body came
+// before it. Theres no Content-Length to check upfront so each chunk gets
+// checked before any of it is read. The size could be anything up to
+// u64::MAX so its checked against whats left instead of being added
+pub(crate) fn parse_chunk_size(line: &[u8], body_size: u64, max_body_size: u64) -> Result<u64, HTTPError> {
+    // chunk extensions are allowed but nothing here uses them
+    let line = String::from_utf8_lossy(line);
+    let size = line.split(';').next().unwrap_or_default().trim();
+    let size = match u64::from_str_radix(size, 16) {
+        Ok(size) => size,
+        Err(_) => return Err(HTTPError::InvalidContent),
+    };
+
+    let remaining = max_body_size.saturating_sub(body_size);
+    match size > remaining {
+        true => Err(HTTPError::ContentTooLarge),
+        false => Ok(size),
+    }
 }
 
-fn process_query_string(queries: &str) -> Result<HashMap<String, String>, HTTPError> {
-    let map = queries.split("&")
-        .map(|decleration| {
-            match decleration.split_once("=") {
-                None => Err(HTTPError::InvalidPath),
-                Some((l, r)) => Ok((l.to_owned(), r.to_owned()))
+fn read_chunked_body<R: BufRead>(reader: &mut R, limits: &RequestLimits) -> Result<(Vec<u8>, Headers), HTTPError> {
+    let mut chunks = ChunkedReader::new(reader, limits.max_body_size as u64);
+    let mut content = Vec::new();
+    if let Err(e) = chunks.read_to_end(&mut content) {
+        return Err(chunks.error.unwrap_or(read_error(e, HTTPError::InvalidContent)));
+    }
+
+    let trailers = chunks.trailers(limits)?;
+    Ok((content, trailers))
+}
+
+// undoes the chunked encoding as its read, so an upload can go straight into
+// the multipart parser without the whole body being held anywhere first
+struct ChunkedReader<'a, R> {
+    reader: &'a mut R,
+    max_body_size: u64,
+    // how much of the body has been read, not counting the chunk sizes
+    body_size: u64,
+    // whats left of the chunk being read
+    remaining: u64,
+    started: bool,
+    done: bool,
+    // an io error cant say whether it was too big or just broken so the
+    // reason is kept here
+    error: Option<HTTPError>,
+}
+
+impl<'a, R: BufRead> ChunkedReader<'a, R> {
+    fn new(reader: &'a mut R, max_body_size: u64) -> Self {
+        Self {
+            reader,
+            max_body_size,
+            body_size: 0,
+            remaining: 0,
+            started: false,
+            done: false,
+            error: None,
+        }
+    }
+
+    // the size of the next chunk, 0 for the last one
+    fn next_chunk(&mut self) -> Result<u64, HTTPError> {
+        // every chunk but the first comes after the \r\n ending the one before
+        if self.started {
+            let mut line_end = [0_u8; 2];
+            match self.reader.read_exact(&mut line_end) {
+                Ok(_) if &line_end == b"\r\n" => {},
+                Ok(_) => return Err(HTTPError::InvalidContent),
+                Err(e) => return Err(read_error(e, HTTPError::InvalidContent)),
             }
-        })
-        .collect::<Result<HashMap<String, String>, HTTPError>>();
+        }
+        self.started = true;
 
-    map
+        let mut size_line = Vec::new();
+        match self.reader.by_ref().take(MAX_CHUNK_SIZE_LINE as u64).read_until(b'\n', &mut size_line) {
+            Ok(0) => return Err(HTTPError::InvalidContent),
+            Err(e) => return Err(read_error(e, HTTPError::InvalidContent)),
+            Ok(_) if line_too_long(&size_line, MAX_CHUNK_SIZE_LINE) => return Err(HTTPError::InvalidContent),
+            Ok(_) => {},
+        }
+        parse_chunk_size(&size_line, self.body_size, self.max_body_size)
+    }
+
+    // trailers are formatted just like the header and end the same way
+    fn trailers(self, limits: &RequestLimits) -> Result<Headers, HTTPError> {
+        Headers::from_str(&split_header(self.reader, limits)?)
+    }
+
+    fn fail(&mut self, error: HTTPError) -> io::Error {
+        self.error = Some(error);
+        io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 && !self.done {
+            match self.next_chunk() {
+                Ok(0) => self.done = true,
+                Ok(size) => self.remaining = size,
+                Err(e) => return Err(self.fail(e)),
+            }
+        }
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let to_read = buf.len().min(self.remaining.min(usize::MAX as u64) as usize);
+        match self.reader.read(&mut buf[..to_read]) {
+            Ok(0) => Err(self.fail(HTTPError::InvalidContent)),
+            Ok(read) => {
+                self.remaining -= read as u64;
+                self.body_size += read as u64;
+                Ok(read)
+            },
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => Err(e),
+            Err(e) => Err(self.fail(read_error(e, HTTPError::InvalidContent))),
+        }
+    }
+}
+
+// the multipart parser stops at the closing boundary, anything after that
+// is an epilogue thats thrown away so the next request starts in the right place
+fn read_upload<R: Read>(body: &mut R, boundary: &str, limits: &MultipartLimits) -> Result<Multipart, HTTPError> {
+    let multipart = Multipart::parse(body.by_ref(), boundary, limits)?;
+    match io::copy(body, &mut io::sink()) {
+        Ok(_) => Ok(multipart),
+        Err(e) => Err(read_error(e, HTTPError::InvalidContent)),
+    }
 }
 
-fn split_header(mut reader: BufReader<&mut TcpStream>) -> Result<(String, BufReader<&mut TcpStream>), HTTPError> {
-        // some how split the body from the header
-    // this will be painfull and horrible
+fn split_header<R: BufRead>(reader: &mut R, limits: &RequestLimits) -> Result<String, HTTPError> {
+    // reads line by line untill the empty line that splits the header from the body
     let mut buf = Vec::new();
+    let mut header_count = 0;
     loop {
-        match reader.read_until(b'\r', &mut buf) {
-            Ok(_) => {},
-            Err(_) => return Err(HTTPError::InvalidHeader),
+        let line_start = buf.len();
+        // the empty line at the end counts towards the size as well
+        let remaining = limits.max_header_size.saturating_sub(line_start);
+        if remaining == 0 {
+            return Err(HTTPError::HeaderTooLarge);
         }
-        let mut minor_buf = [0_u8; 3];
-        match reader.read_exact(&mut minor_buf) {
+
+        match reader.by_ref().take(remaining as u64).read_until(b'\n', &mut buf) {
+            Ok(0) => return Err(HTTPError::InvalidHeader),
+            Ok(_) if line_too_long(&buf[line_start..], remaining) => return Err(HTTPError::HeaderTooLarge),
             Ok(_) => {},
-            Err(_) => return Err(HTTPError::InvalidHeader),
+            Err(e) => return Err(read_error(e, HTTPError::InvalidHeader)),
         }
-        if minor_buf == [b'\n', b'\r', b'\n'] {
-            buf.extend(minor_buf);
+
+        let line = &buf[line_start..];
+        if line == b"\r\n" || line == b"\n" {
             break;
-        } else {
-            // failed to find the double newline but still need to extend the buffer
-            // as its still part from the header
-            buf.extend(minor_buf);
-            continue;
+        }
+
+        header_count += 1;
+        if header_count > limits.max_headers {
+            return Err(HTTPError::HeaderTooLarge);
         }
     }
 
@@ -408,63 +1287,62 @@ fn split_header(mut reader: BufReader<&mut TcpStream>) -> Result<(String, BufRea
         Err(_) => return Err(HTTPError::InvalidHeader),
     };
 
-    Ok((header_string, reader))
+    Ok(header_string)
 }
 
-#[derive(Debug)]
-pub struct GETRequest {
-    pub path: String,
-    query_string: HashMap<String, String>,
-    ip: IpAddr,
+// a line read with take(limit) that filled the whole limit without getting
+// to the new line was cut off, a line that just ran into the end of the
+// stream is shorter than that and gets caught by whatever parses it
+fn line_too_long(line: &[u8], limit: usize) -> bool {
+    line.len() >= limit && !line.ends_with(b"\n")
 }
 
-impl GETRequest {
-    pub fn new(line: HTTPRequestLine, reader: BufReader<&mut TcpStream>) -> Result<Self, HTTPError> {
-        let (path, query_string) = match line.path.split_once("?") {
-            Some((left, right)) => {
-                let queries = process_query_string(right)?;
-                (left.to_owned(), queries)
-            },
-            None => (line.path, HashMap::new())
-        };
-
-        let (header, _) = split_header(reader)?;
-
-        let mut ip_str = "";
-
-        for line in header.lines() {
-            if line.starts_with("X-Forwarded-For: ") {
-                ip_str = &line[17..];
-            }
-        }
+// the most ranges one request can ask for before we just send the whole file
+const MAX_RANGES: usize = 16;
 
-        let ip = match IpAddr::from_str(ip_str) {
-            Ok(ip) => ip,
-            Err(_) => Err(HTTPError::FailedToObtainIP)?,
-        };
+#[derive(Debug, PartialEq, Eq)]
+pub enum ByteRanges {
+    Full,
+    // inclusive start and end of each range, already clamped to the file
+    Partial(Vec<(u64, u64)>),
+    Unsatisfiable,
+}
 
-        Ok(Self {
-            path,
-            query_string,
-            ip,
-        })
-    }
+// W/"abc" and "abc" are the same tag when its only being used to check if
+// the client can keep using its cached copy
+fn weak_etag_compare(left: &str, right: &str) -> bool {
+    let left = left.strip_prefix("W/").unwrap_or(left);
+    let right = right.strip_prefix("W/").unwrap_or(right);
+    left == right
+}
 
-    pub fn get_query(&self, key: &str) -> Option<&String> {
-        self.query_string.get(key)
+// a read that ran out of time gets a 408 instead of whatever error it would
+// have been if the data was just wrong
+pub(crate) fn read_error(error: std::io::Error, otherwise: HTTPError) -> HTTPError {
+    match is_timeout(&error) {
+        true => HTTPError::RequestTimeout,
+        false => otherwise,
     }
 }
 
 #[derive(Debug)]
 pub struct HTTPRequestLine {
     kind: HTTPType,
+    // already decoded and normalized, see url::normalize_path
     pub path: String,
+    // everything after the '?' as it was sent
+    pub query: Option<String>,
+    version: HTTPVersion,
 }
 
 impl HTTPRequestLine {
     pub fn get_kind(&self) -> HTTPType {
         self.kind
     }
+
+    pub fn get_version(&self) -> HTTPVersion {
+        self.version
+    }
 }
 
 impl std::str::FromStr for HTTPRequestLine {
@@ -474,47 +1352,103 @@ impl std::str::FromStr for HTTPRequestLine {
 
         let kind = match groups.next() {
             None => return Err(HTTPError::InvalidRequestType),
-            Some(kind) => {
-                match kind {
-                    "GET" => HTTPType::Get,
-                    "POST" => HTTPType::Post,
-                    _ => return Err(HTTPError::InvalidRequestType),
-                }
-            }
+            Some(kind) => HTTPType::from_str(kind)?,
         };
 
-        let path = match groups.next() {
+        let target = match groups.next() {
             None => return Err(HTTPError::InvalidPath),
-            Some(s) => s.replace("%20", " ")
+            Some(s) => s,
         };
 
-        // garuntees unwrap wont fail later
-        if !path.starts_with('/') {
-            return Err(HTTPError::InvalidPath);
-        }
+        let version = match groups.next() {
+            None => return Err(HTTPError::InvalidVersion),
+            Some(version) => HTTPVersion::from_str(version)?,
+        };
 
-        // prevents people from theoretically escaping the website folder
-        // preventing them from accsessing any file on my PC!
-        if let Some(_) = path.matches("../").next() {
-            return Err(HTTPError::InvalidPath);
+        // garuntees unwrap wont fail later, the only exception being the
+        // 'OPTIONS * HTTP/1.1' form which asks about the server as a whole
+        if target == "*" && kind == HTTPType::Options {
+            return Ok(Self { kind, path: target.to_owned(), query: None, version });
         }
 
-        match groups.next() {
-            None => return Err(HTTPError::InvalidVersion),
-            Some(_) => {},
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, Some(query.to_owned())),
+            None => (target, None),
+        };
+
+        // prevents people from escaping the website folder with '..' however
+        // its spelled, preventing them from accsessing any file on my PC!
+        let path = match url::normalize_path(path) {
+            Some(path) => path,
+            None => return Err(HTTPError::InvalidPath),
         };
 
         Ok(Self {
             kind,
             path,
+            query,
+            version,
         })
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HTTPVersion {
+    Http10,
+    Http11,
+}
+
+impl std::str::FromStr for HTTPVersion {
+    type Err = HTTPError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "HTTP/1.0" => Ok(Self::Http10),
+            "HTTP/1.1" => Ok(Self::Http11),
+            _ => Err(HTTPError::InvalidVersion),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum HTTPType {
-    Post,
     Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Options,
+    Patch,
+}
+
+impl std::str::FromStr for HTTPType {
+    type Err = HTTPError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // methods are case sensitive so "get" is not a GET
+        match s {
+            "GET" => Ok(Self::Get),
+            "HEAD" => Ok(Self::Head),
+            "POST" => Ok(Self::Post),
+            "PUT" => Ok(Self::Put),
+            "DELETE" => Ok(Self::Delete),
+            "OPTIONS" => Ok(Self::Options),
+            "PATCH" => Ok(Self::Patch),
+            _ => Err(HTTPError::InvalidRequestType),
+        }
+    }
+}
+
+impl std::fmt::Display for HTTPType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Get => write!(f, "GET"),
+            Self::Head => write!(f, "HEAD"),
+            Self::Post => write!(f, "POST"),
+            Self::Put => write!(f, "PUT"),
+            Self::Delete => write!(f, "DELETE"),
+            Self::Options => write!(f, "OPTIONS"),
+            Self::Patch => write!(f, "PATCH"),
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -528,6 +1462,26 @@ pub enum HTTPError {
     InvalidContentLength,
     InvalidContent,
     FailedToObtainIP,
+    InvalidTransferEncoding,
+    ConnectionClosed,
+    ContentTooLarge,
+    RequestLineTooLong,
+    HeaderTooLarge,
+    RequestTimeout,
+}
+
+impl HTTPError {
+    // what gets sent back when a request cant be read, most things are just
+    // a bad request but going over one of the limits has its own code
+    pub fn get_status_code(&self) -> StatusCode {
+        match self {
+            Self::ContentTooLarge => StatusCode::ContentTooLarge,
+            Self::RequestLineTooLong => StatusCode::UriTooLong,
+            Self::HeaderTooLarge => StatusCode::RequestHeaderFieldsTooLarge,
+            Self::RequestTimeout => StatusCode::RequestTimeout,
+            _ => StatusCode::BadRequest,
+        }
+    }
 }
 
 impl std::fmt::Display for HTTPError {
@@ -542,6 +1496,12 @@ impl std::fmt::Display for HTTPError {
             Self::InvalidContentLength => writeln!(f, "Invalid or missing Content-Length"),
             Self::InvalidContent => writeln!(f, "Content to short for Content-Length or invalid Content"),
             Self::FailedToObtainIP => writeln!(f, "Unable to get IP address of the client"),
+            Self::InvalidTransferEncoding => writeln!(f, "Invalid or unsupported Transfer-Encoding"),
+            Self::ConnectionClosed => writeln!(f, "Connection closed by the client"),
+            Self::ContentTooLarge => writeln!(f, "Content was larger than allowed"),
+            Self::RequestLineTooLong => writeln!(f, "Request line was longer than allowed"),
+            Self::HeaderTooLarge => writeln!(f, "Header was larger than allowed or had too many fields"),
+            Self::RequestTimeout => writeln!(f, "Request took too long to be sent"),
         }
     }
 }
@@ -664,4 +1624,455 @@ pub fn turn_system_time_to_http_date(time: SystemTime) -> String {
     buf[24] = b'0' + (sec % 10) as u8;
 
     String::from_utf8_lossy(&buf).to_string()
-}
\ No newline at end of file
+}
+
+// the reverse of turn_system_time_to_http_date, clients are allowed to send any of:
+// Sun, 06 Nov 1994 08:49:37 GMT  (the one everyone uses)
+// Sunday, 06-Nov-94 08:49:37 GMT (obsolete RFC 850)
+// Sun Nov  6 08:49:37 1994       (obsolete asctime)
+pub fn parse_http_date(date: &str) -> Option<SystemTime> {
+    let parts = date.split_whitespace().collect::<Vec<&str>>();
+
+    let (day, month, year, time) = match parts.as_slice() {
+        [_, day, month, year, time, "GMT"] => (*day, *month, year.parse::<i64>().ok()?, *time),
+        [_, date, time, "GMT"] => {
+            let mut date = date.split('-');
+            let day = date.next()?;
+            let month = date.next()?;
+            // two digit years, anything that looks like the future is last century
+            let year = match date.next()?.parse::<i64>().ok()? {
+                year if year < 70 => year + 2000,
+                year => year + 1900,
+            };
+            (day, month, year, *time)
+        },
+        [_, month, day, time, year] => (*day, *month, year.parse::<i64>().ok()?, *time),
+        _ => return None,
+    };
+
+    let day = day.parse::<i64>().ok()?;
+    let month = match month {
+        "Jan" => 1,
+        "Feb" => 2,
+        "Mar" => 3,
+        "Apr" => 4,
+        "May" => 5,
+        "Jun" => 6,
+        "Jul" => 7,
+        "Aug" => 8,
+        "Sep" => 9,
+        "Oct" => 10,
+        "Nov" => 11,
+        "Dec" => 12,
+        _ => return None,
+    };
+
+    let mut time = time.split(':').map(|part| part.parse::<i64>().ok());
+    let hour = time.next()??;
+    let min = time.next()??;
+    let sec = time.next()??;
+
+    if !(1..=31).contains(&day) || hour > 23 || min > 59 || sec > 60 {
+        return None;
+    }
+
+    // days since the epoch for a civil date, from Howard Hinnant's date algorithms
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    let seconds = days * 86400 + hour * 3600 + min * 60 + sec;
+    if seconds < 0 {
+        return None;
+    }
+
+    Some(UNIX_EPOCH + Duration::from_secs(seconds as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(request: &str) -> Result<Request, HTTPError> {
+        Request::new(&mut request.as_bytes(), &RequestLimits::default())
+    }
+
+    // a GET for / with extra header lines
+    fn get(headers: &str) -> Request {
+        parse(&format!("GET / HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\n{}\r\n", headers)).unwrap()
+    }
+
+    #[test]
+    fn if_none_match_compares_etags() {
+        let etag = Some("\"abc\"");
+        assert!(get("If-None-Match: \"abc\"\r\n").is_not_modified(etag, None));
+        assert!(get("If-None-Match: \"xyz\", W/\"abc\"\r\n").is_not_modified(etag, None));
+        assert!(get("If-None-Match: *\r\n").is_not_modified(etag, None));
+        assert!(!get("If-None-Match: \"xyz\"\r\n").is_not_modified(etag, None));
+        // nothing to compare against means the client gets the file
+        assert!(!get("If-None-Match: *\r\n").is_not_modified(None, None));
+        assert!(!get("").is_not_modified(etag, None));
+    }
+
+    #[test]
+    fn if_modified_since_ignores_fractions_of_a_second() {
+        let modified = UNIX_EPOCH + Duration::from_millis(1_700_000_000_500);
+        let same_second = turn_system_time_to_http_date(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        let before = turn_system_time_to_http_date(UNIX_EPOCH + Duration::from_secs(1_699_999_999));
+
+        assert!(get(&format!("If-Modified-Since: {}\r\n", same_second)).is_not_modified(None, Some(modified)));
+        assert!(!get(&format!("If-Modified-Since: {}\r\n", before)).is_not_modified(None, Some(modified)));
+        assert!(!get("If-Modified-Since: yesterday\r\n").is_not_modified(None, Some(modified)));
+    }
+
+    #[test]
+    fn if_none_match_wins_over_if_modified_since() {
+        let modified = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let date = turn_system_time_to_http_date(modified);
+        let request = get(&format!("If-None-Match: \"old\"\r\nIf-Modified-Since: {}\r\n", date));
+        assert!(!request.is_not_modified(Some("\"new\""), Some(modified)));
+    }
+
+    #[test]
+    fn only_reads_can_be_not_modified() {
+        let request = parse("POST / HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\nIf-None-Match: *\r\nContent-Length: 0\r\n\r\n").unwrap();
+        assert!(!request.is_not_modified(Some("\"abc\""), None));
+    }
+
+    #[test]
+    fn byte_ranges_are_clamped_to_the_file() {
+        let ranges = |range: &str| get(&format!("Range: {}\r\n", range)).get_byte_ranges(1000);
+        assert_eq!(ranges("bytes=0-499"), ByteRanges::Partial(vec![(0, 499)]));
+        assert_eq!(ranges("bytes=900-5000"), ByteRanges::Partial(vec![(900, 999)]));
+        assert_eq!(ranges("bytes=990-"), ByteRanges::Partial(vec![(990, 999)]));
+        assert_eq!(ranges("bytes=-100"), ByteRanges::Partial(vec![(900, 999)]));
+        assert_eq!(ranges("bytes=-5000"), ByteRanges::Partial(vec![(0, 999)]));
+        assert_eq!(ranges("bytes=0-0, 10-19"), ByteRanges::Partial(vec![(0, 0), (10, 19)]));
+    }
+
+    #[test]
+    fn ranges_outside_the_file_are_unsatisfiable() {
+        let ranges = |range: &str| get(&format!("Range: {}\r\n", range)).get_byte_ranges(1000);
+        assert_eq!(ranges("bytes=1000-"), ByteRanges::Unsatisfiable);
+        assert_eq!(ranges("bytes=-0"), ByteRanges::Unsatisfiable);
+        // one good range is enough
+        assert_eq!(ranges("bytes=5000-6000, 0-9"), ByteRanges::Partial(vec![(0, 9)]));
+    }
+
+    #[test]
+    fn ranges_that_dont_make_sense_get_the_whole_file() {
+        let ranges = |range: &str| get(&format!("Range: {}\r\n", range)).get_byte_ranges(1000);
+        assert_eq!(get("").get_byte_ranges(1000), ByteRanges::Full);
+        assert_eq!(ranges("items=0-10"), ByteRanges::Full);
+        assert_eq!(ranges("bytes=10-5"), ByteRanges::Full);
+        assert_eq!(ranges("bytes=abc"), ByteRanges::Full);
+        // so many ranges its more likely an attack than a real client
+        assert_eq!(ranges(&format!("bytes={}", vec!["0-0"; MAX_RANGES + 1].join(","))), ByteRanges::Full);
+    }
+
+    #[test]
+    fn if_range_needs_a_strong_match() {
+        let modified = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let etag = Some("\"abc\"");
+        assert!(get("").if_range_matches(etag, Some(modified)));
+        assert!(get("If-Range: \"abc\"\r\n").if_range_matches(etag, Some(modified)));
+        assert!(!get("If-Range: \"old\"\r\n").if_range_matches(etag, Some(modified)));
+        assert!(!get("If-Range: W/\"abc\"\r\n").if_range_matches(etag, Some(modified)));
+        let date = turn_system_time_to_http_date(modified);
+        assert!(get(&format!("If-Range: {}\r\n", date)).if_range_matches(etag, Some(modified)));
+        assert!(!get(&format!("If-Range: {}\r\n", date)).if_range_matches(etag, Some(modified + Duration::from_secs(1))));
+    }
+
+    #[test]
+    fn known_content_types_ignore_parameters() {
+        let request = parse("POST /api/mail HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\nContent-Type: Text/Plain; charset=UTF-8\r\nContent-Length: 2\r\n\r\nhi").unwrap();
+        assert!(matches!(request.get_content_type(), ContentType::PlainText));
+        assert_eq!(request.get_mime().unwrap().get_param("charset"), Some("UTF-8"));
+    }
+
+    #[test]
+    fn unknown_content_types_are_not_an_error() {
+        for mime in ["application/xml", "text/csv", "application/pdf; name=a.pdf"] {
+            let request = parse(&format!("POST /api/mail HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\nContent-Type: {}\r\nContent-Length: 2\r\n\r\nhi", mime)).unwrap();
+            assert!(matches!(request.get_content_type(), ContentType::Unknown), "{}", mime);
+            assert!(mime.starts_with(&request.get_mime().unwrap().essence()), "{}", mime);
+        }
+
+        let request = parse("GET / HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\nContent-Type: application/pdf\r\n\r\n").unwrap();
+        assert_eq!(request.get_path(), "/");
+    }
+
+    #[test]
+    fn huge_chunk_sizes_cant_overflow_the_body_limit() {
+        let mut request = String::from("POST /api/mail HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\nTransfer-Encoding: chunked\r\n\r\n");
+        request.push_str("1\r\nX\r\nffffffffffffffff\r\n");
+        request.push_str(&"a".repeat(64 * 1024));
+        assert!(matches!(parse(&request), Err(HTTPError::ContentTooLarge)));
+
+        let request = "POST /api/mail HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\nTransfer-Encoding: chunked\r\n\r\nffffffffffffffff\r\n";
+        assert!(matches!(parse(request), Err(HTTPError::ContentTooLarge)));
+    }
+
+    #[test]
+    fn chunked_bodies_are_put_back_together() {
+        let request = parse("POST /api/mail HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2;ext=1\r\nde\r\n0\r\nX-Sum: 5\r\n\r\n").unwrap();
+        assert_eq!(request.get_data(), b"abcde");
+        assert_eq!(request.trailers().get("X-Sum"), Some("5"));
+    }
+
+    #[test]
+    fn preflights_only_pass_for_allowed_origins() {
+        let cors = CorsPolicy {
+            allowed_origins: vec![String::from("https://ok.example.com")],
+            ..CorsPolicy::default()
+        };
+        let preflight = |origin: &str| {
+            let request = parse(&format!("OPTIONS /api/mail HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\nOrigin: {}\r\nAccess-Control-Request-Method: POST\r\nAccess-Control-Request-Headers: X-Secret, Content-Type\r\n\r\n", origin)).unwrap();
+            Response::new_preflight(&request, "POST, OPTIONS", &cors)
+        };
+
+        let allowed = preflight("https://ok.example.com");
+        assert_eq!(allowed.header("Access-Control-Allow-Origin"), Some("https://ok.example.com"));
+        assert_eq!(allowed.header("Access-Control-Allow-Methods"), Some("POST, OPTIONS"));
+        // only the listed headers, not whatever was asked for
+        assert_eq!(allowed.header("Access-Control-Allow-Headers"), Some("Content-Type"));
+        assert_eq!(allowed.header("Vary"), Some("Origin"));
+
+        let denied = preflight("https://evil.example.com");
+        assert_eq!(denied.header("Access-Control-Allow-Origin"), None);
+        assert_eq!(denied.header("Access-Control-Allow-Methods"), None);
+        assert_eq!(denied.header("Allow"), Some("POST, OPTIONS"));
+    }
+
+    #[test]
+    fn nothing_is_allowed_by_default() {
+        let request = parse("OPTIONS /api/mail HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\nOrigin: https://a.example.com\r\nAccess-Control-Request-Method: POST\r\n\r\n").unwrap();
+        assert_eq!(CorsPolicy::default().allowed_origin(&request), None);
+        let response = Response::new_preflight(&request, "POST, OPTIONS", &CorsPolicy::default());
+        assert_eq!(response.header("Access-Control-Allow-Origin"), None);
+    }
+
+    #[test]
+    fn chunked_bodies_without_trailers() {
+        let request = parse("POST / HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\nTransfer-Encoding: Chunked\r\n\r\na\r\n0123456789\r\n0\r\n\r\n").unwrap();
+        assert_eq!(request.get_data(), b"0123456789");
+        assert_eq!(request.get_data_length(), 10);
+        assert!(request.trailers().get("X-Sum").is_none());
+    }
+
+    #[test]
+    fn broken_chunks_are_invalid() {
+        let chunked = |body: &str| parse(&format!("POST / HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\nTransfer-Encoding: chunked\r\n\r\n{}", body));
+        // not hex
+        assert!(matches!(chunked("zz\r\nabc\r\n0\r\n\r\n"), Err(HTTPError::InvalidContent)));
+        // shorter than it said
+        assert!(matches!(chunked("5\r\nabc"), Err(HTTPError::InvalidContent)));
+        // no new line after the chunk
+        assert!(matches!(chunked("3\r\nabcXY0\r\n\r\n"), Err(HTTPError::InvalidContent)));
+        // never finished
+        assert!(matches!(chunked("3\r\nabc\r\n"), Err(HTTPError::InvalidContent)));
+    }
+
+    #[test]
+    fn only_plain_chunked_is_accepted() {
+        let body = "3\r\nabc\r\n0\r\n\r\n";
+        let both = format!("POST / HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n{}", body);
+        assert!(matches!(parse(&both), Err(HTTPError::InvalidTransferEncoding)));
+        let http10 = format!("POST / HTTP/1.0\r\nX-Forwarded-For: 1.2.3.4\r\nTransfer-Encoding: chunked\r\n\r\n{}", body);
+        assert!(matches!(parse(&http10), Err(HTTPError::InvalidTransferEncoding)));
+        let gzip = format!("POST / HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\nTransfer-Encoding: gzip, chunked\r\n\r\n{}", body);
+        assert!(matches!(parse(&gzip), Err(HTTPError::InvalidTransferEncoding)));
+    }
+
+    #[test]
+    fn responses_without_a_length_are_chunked() {
+        let chunks = vec![b"abc".to_vec()].into_iter();
+        let mut response = Response::new_ok(ContentType::PlainText, None, Body::Chunked(Box::new(chunks)));
+        let header = response.header_string();
+        assert!(header.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(!header.contains("Content-length"));
+
+        // HTTP/1.0 cant read chunks so the end of the body is the connection closing
+        response.set_keep_alive(true);
+        response.set_version(HTTPVersion::Http10);
+        let header = response.header_string();
+        assert!(!header.contains("Transfer-Encoding"));
+        assert!(header.contains("Connection: close\r\n"));
+    }
+
+    #[test]
+    fn responses_are_compressed_when_its_worth_it() {
+        let text = "compress me please ".repeat(100);
+        let mut response = Response::builder(StatusCode::Ok)
+            .content_type(ContentType::Html)
+            .etag("\"abc\"")
+            .body(text.clone().into_bytes())
+            .build();
+        response.compress(Encoding::Gzip);
+        assert_eq!(response.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.header("ETag"), Some("\"abc-gzip\""));
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+        assert!(response.body.len().unwrap() < text.len() as u64);
+
+        // too small to bother with, but it still varies
+        let mut response = Response::new_ok(ContentType::Html, None, b"<p>hi</p>".to_vec());
+        response.compress(Encoding::Gzip);
+        assert_eq!(response.header("Content-Encoding"), None);
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+
+        // images are already compressed
+        let mut response = Response::new_ok(ContentType::Image(ImageType::Png), None, text.into_bytes());
+        response.compress(Encoding::Gzip);
+        assert_eq!(response.header("Content-Encoding"), None);
+        assert_eq!(response.header("Vary"), None);
+    }
+
+    #[test]
+    fn accept_encoding_picks_the_encoding() {
+        assert_eq!(get("Accept-Encoding: gzip, br;q=0.9\r\n").preferred_encoding(&compression::DYNAMIC_ENCODINGS), Encoding::Gzip);
+        assert_eq!(get("").preferred_encoding(&compression::DYNAMIC_ENCODINGS), Encoding::Identity);
+    }
+
+    #[test]
+    fn query_strings_are_decoded() {
+        let request = parse("GET /search?q=hello+world&tag=a&tag=b%21 HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\n\r\n").unwrap();
+        assert_eq!(request.get_path(), "/search");
+        assert_eq!(request.get_query("q"), Some("hello world"));
+        assert_eq!(request.get_query_all("tag"), vec!["a", "b!"]);
+
+        let broken = parse("GET /search?q=%zz HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\n\r\n");
+        assert!(matches!(broken, Err(HTTPError::InvalidPath)));
+    }
+
+    #[test]
+    fn form_bodies_are_parsed_like_queries() {
+        let body = "name=Ada+Lovelace&email=ada%40example.com";
+        let request = parse(&format!("POST /api/mail HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: {}\r\n\r\n{}", body.len(), body)).unwrap();
+        let form = request.get_form().unwrap();
+        assert_eq!(form.get("name"), Some("Ada Lovelace"));
+        assert_eq!(form.get("email"), Some("ada@example.com"));
+
+        let json = parse("POST /api/mail HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\nContent-Type: application/json\r\nContent-Length: 2\r\n\r\n{}").unwrap();
+        assert!(matches!(json.get_form(), Err(HTTPError::InvalidContentType)));
+
+        let broken = parse("POST /api/mail HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: 4\r\n\r\na=%z").unwrap();
+        assert!(matches!(broken.get_form(), Err(HTTPError::InvalidContent)));
+    }
+
+    #[test]
+    fn accept_picks_the_content_type() {
+        let available = [ContentType::OctetStream, ContentType::Json];
+        let preferred = |accept: &str| get(&format!("Accept: {}\r\n", accept)).preferred_content_type(&available);
+        assert!(matches!(get("").preferred_content_type(&available), Some(ContentType::OctetStream)));
+        assert!(matches!(preferred("application/json"), Some(ContentType::Json)));
+        assert!(matches!(preferred("application/*"), Some(ContentType::OctetStream)));
+        assert!(matches!(preferred("application/octet-stream;q=0.5, application/json"), Some(ContentType::Json)));
+        // the more specific range counts even when the wildcard has a higher q
+        assert!(matches!(preferred("*/*;q=1, application/octet-stream;q=0"), Some(ContentType::Json)));
+        assert!(preferred("text/html").is_none());
+        assert!(preferred("application/json;q=0, application/octet-stream;q=0").is_none());
+    }
+
+    #[test]
+    fn json_bodies_are_parsed() {
+        #[derive(serde::Deserialize)]
+        struct Message {
+            name: String,
+            count: u32,
+        }
+
+        let body = r#"{"name":"ada","count":3}"#;
+        let request = parse(&format!("POST / HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\nContent-Type: application/json; charset=utf-8\r\nContent-Length: {}\r\n\r\n{}", body.len(), body)).unwrap();
+        let message = request.json::<Message>().unwrap();
+        assert_eq!(message.name, "ada");
+        assert_eq!(message.count, 3);
+
+        let wrong_shape = parse("POST / HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\nContent-Type: application/json\r\nContent-Length: 12\r\n\r\n{\"name\":\"a\"}").unwrap();
+        assert!(matches!(wrong_shape.json::<Message>(), Err(HTTPError::InvalidContent)));
+
+        let not_json = parse(&format!("POST / HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}", body.len(), body)).unwrap();
+        assert!(matches!(not_json.json::<Message>(), Err(HTTPError::InvalidContentType)));
+    }
+
+    #[test]
+    fn json_responses_are_serialized() {
+        let response = Response::json(&vec!["a", "b"]);
+        assert_eq!(response.get_code(), StatusCode::Ok);
+        assert!(matches!(response.content_type, ContentType::Json));
+        assert!(matches!(&response.body, Body::Bytes(data) if data == br#"["a","b"]"#));
+    }
+
+    #[test]
+    fn broken_content_types_are_still_an_error() {
+        assert!(parse("POST / HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\nContent-Type: nonsense\r\nContent-Length: 0\r\n\r\n").is_err());
+    }
+
+    const UPLOAD: &str = "--XyZ\r\nContent-Disposition: form-data; name=\"email\"\r\n\r\na@b.c\r\n--XyZ--\r\n";
+
+    fn upload(headers: &str, body: &str) -> String {
+        format!("POST /api/mail HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\nContent-Type: multipart/form-data; boundary=XyZ\r\n{}\r\n{}", headers, body)
+    }
+
+    #[test]
+    fn uploads_are_parsed_as_theyre_read() {
+        let raw = upload(&format!("Content-Length: {}\r\n", UPLOAD.len()), UPLOAD);
+        let request = parse(&raw).unwrap();
+        assert_eq!(request.get_multipart().unwrap().text("email"), Some("a@b.c"));
+        // the body went straight to the parser so theres no copy of it
+        assert_eq!(request.get_data(), b"");
+        assert_eq!(request.get_data_length(), UPLOAD.len());
+
+        let chunked = format!("{:x}\r\n{}\r\n0\r\nX-Sum: 1\r\n\r\n", UPLOAD.len(), UPLOAD);
+        let request = parse(&upload("Transfer-Encoding: chunked\r\n", &chunked)).unwrap();
+        assert_eq!(request.get_multipart().unwrap().text("email"), Some("a@b.c"));
+        assert_eq!(request.trailers().get("X-Sum"), Some("1"));
+    }
+
+    #[test]
+    fn the_next_request_starts_after_the_upload() {
+        let body = format!("{}epilogue", UPLOAD);
+        let raw = format!("{}GET /next HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\n\r\n", upload(&format!("Content-Length: {}\r\n", body.len()), &body));
+        let mut reader = raw.as_bytes();
+        let request = Request::new(&mut reader, &RequestLimits::default()).unwrap();
+        assert_eq!(request.get_multipart().unwrap().text("email"), Some("a@b.c"));
+        let next = Request::new(&mut reader, &RequestLimits::default()).unwrap();
+        assert_eq!(next.get_path(), "/next");
+    }
+
+    #[test]
+    fn uploads_go_by_the_multipart_limits() {
+        let limits = RequestLimits {
+            max_body_size: 8,
+            multipart: MultipartLimits {
+                max_total_size: UPLOAD.len() as u64,
+                ..MultipartLimits::default()
+            },
+            ..RequestLimits::default()
+        };
+        let raw = upload(&format!("Content-Length: {}\r\n", UPLOAD.len()), UPLOAD);
+        assert!(Request::new(&mut raw.as_bytes(), &limits).is_ok());
+
+        let body = format!("{}epilogue", UPLOAD);
+        let raw = upload(&format!("Content-Length: {}\r\n", body.len()), &body);
+        assert!(matches!(Request::new(&mut raw.as_bytes(), &limits), Err(HTTPError::ContentTooLarge)));
+
+        let chunked = format!("{:x}\r\n{}\r\n0\r\n\r\n", body.len(), body);
+        let raw = upload("Transfer-Encoding: chunked\r\n", &chunked);
+        assert!(matches!(Request::new(&mut raw.as_bytes(), &limits), Err(HTTPError::ContentTooLarge)));
+    }
+
+    #[test]
+    fn broken_uploads_are_invalid() {
+        let short = upload(&format!("Content-Length: {}\r\n", UPLOAD.len() + 10), UPLOAD);
+        assert!(matches!(parse(&short), Err(HTTPError::InvalidContent)));
+
+        let no_boundary = "POST / HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\nContent-Type: multipart/form-data\r\nContent-Length: 0\r\n\r\n";
+        assert!(matches!(parse(no_boundary), Err(HTTPError::InvalidContentType)));
+
+        let not_multipart = parse("POST / HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\nContent-Length: 2\r\n\r\nhi").unwrap();
+        assert!(matches!(not_multipart.get_multipart(), Err(HTTPError::InvalidContentType)));
+    }
+}
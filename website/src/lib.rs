@@ -1,4 +1,17 @@
 pub mod thread;
 pub mod apis;
 pub mod http_types;
+pub mod headers;
+pub mod body;
+pub mod compression;
+pub mod status;
+pub mod router;
+pub mod url;
+pub mod multipart;
+pub mod mime;
+pub mod timeout;
+pub mod shutdown;
+pub mod framing;
+#[cfg(all(target_os = "linux", feature = "epoll"))]
+pub mod event_loop;
 pub use http_types as types;
\ No newline at end of file
@@ -1,30 +1,128 @@
 use std::{
     net::{TcpListener, TcpStream},
-    io::{BufReader, Write, Read},
-    fs::{self, Metadata},
-    path::Path,
+    io::{self, BufReader, Read},
+    fs::{self, File, Metadata},
+    path::{Path, PathBuf},
     ffi::OsStr,
-    sync::Arc,
-    time::{SystemTime, Instant, Duration},
+    sync::{Arc, mpsc::{self, Receiver, RecvTimeoutError}},
+    time::{SystemTime, Instant, Duration, UNIX_EPOCH},
+    panic::{self, AssertUnwindSafe},
     env, thread,
 };
 use blog_cli::Cbmd;
-use website::{thread::ThreadPool, http_types::FontType};
+use website::thread::{ThreadPool, PoolConfig, PoolStats};
 use website::apis::ApiRegister;
+use website::multipart::MultipartLimits;
+use website::mime::MimeRegistry;
+use website::shutdown;
+use website::timeout::{self, ConnectionTimeouts, SlowStep};
+#[cfg(not(all(target_os = "linux", feature = "epoll")))]
+use website::timeout::TimedStream;
+#[cfg(all(target_os = "linux", feature = "epoll"))]
+use website::event_loop::{self, Connection, EventLoopConfig};
+use website::router::{Router, Route, StaticDir, render_listing};
+use website::body::{Body, FileParts, FilePart};
+use website::compression::{
+    self, Encoding, encoded_etag,
+    DYNAMIC_ENCODINGS, PRECOMPRESSED_ENCODINGS,
+    MIN_COMPRESS_SIZE, MAX_DYNAMIC_COMPRESS_SIZE,
+};
 use website::types::{
-    ContentType, RequestType,
+    ContentType, CorsPolicy,
     Response, HTTPError,
     turn_system_time_to_http_date,
-    Request, ImageType,
+    Request, RequestLimits, HTTPType,
+    ByteRanges, BYTERANGES_BOUNDARY,
+    StatusCode,
 };
 use lettre::{transport::smtp::authentication::Credentials, Message, message::Mailbox, Transport};
 use lettre::SmtpTransport;
+use serde::{Deserialize, Serialize};
 
 // creds should be filled like:
 // example@example.com
 // password
 const CREDS: &str = include_str!("../secrets");
 
+// keep-alive connections get this long to send another request before
+// they get closed, and can only send so many before being closed anyway
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUESTS_PER_CONNECTION: usize = 100;
+
+// how long requests that were already going get to finish after a SIGTERM
+// before the server exits anyway, enough for a slow upload to get through
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(20);
+
+// a few workers are always around for the normal trickle of visitors and
+// more get started when requests start having to wait, going back down
+// after a minute of nothing to do. Requests waiting for a worker past
+// MAX_QUEUED_CONNECTIONS get whatever full_queue_policy says
+const MIN_WORKERS: usize = 4;
+const MAX_WORKERS: usize = 32;
+const WORKER_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_QUEUED_CONNECTIONS: usize = 64;
+// what a client that got turned away is told to wait before trying again
+const RETRY_AFTER_SECONDS: u64 = 5;
+
+// what happens to a new request when every worker is busy and the queue
+// is full
+#[derive(Debug, Clone, Copy)]
+enum FullQueue {
+    // stop taking connections until theres room, the os backlog fills up
+    // instead. The event loop holds on to requests it already has and keeps
+    // serving everything else in the meantime
+    Block,
+    // send a 503 with Retry-After straight away and hang up
+    Reject,
+}
+
+// idle keep-alive connections only cost a buffer in the event loop so there
+// can be lots of them, this leaves plenty of the open file limit for the
+// files being sent
+#[cfg(all(target_os = "linux", feature = "epoll"))]
+const MAX_CONNECTIONS: usize = 10_000;
+
+// theres only a handful of workers so a client that trickles its request
+// in (or never reads the response) cant be allowed to hold one for long
+const TIMEOUTS: ConnectionTimeouts = ConnectionTimeouts {
+    idle: KEEP_ALIVE_TIMEOUT,
+    read: Duration::from_secs(10),
+    write: Duration::from_secs(10),
+    header: Duration::from_secs(10),
+    body: Duration::from_secs(30),
+};
+
+// nothing on the site needs a big request, the contact form is the biggest
+// thing anyone sends and its limited to way less than this anyway
+const REQUEST_LIMITS: RequestLimits = RequestLimits {
+    max_request_line: 8 * 1024,
+    max_header_size: 16 * 1024,
+    max_headers: 100,
+    max_body_size: 1024 * 1024,
+    // the contact form is the only thing that takes multipart and nothing
+    // in it should need more than this. Its fields are read as text so
+    // they all stay in memory, nothing is big enough to go to a temp file
+    multipart: MultipartLimits {
+        max_parts: 8,
+        max_part_size: 64 * 1024,
+        max_total_size: 128 * 1024,
+        memory_threshold: 64 * 1024,
+        temp_dir: None,
+    },
+};
+
+// anything outside of /api is just a file on disk
+const STATIC_FILE_METHODS: &str = "GET, HEAD, OPTIONS";
+
+// what /api/recentBlogPosts and /api/searchBlog can send, the first is
+// used when the client doesnt say
+const BLOG_POST_CONTENT_TYPES: [ContentType; 2] = [ContentType::OctetStream, ContentType::Json];
+
+// where the site is served from when SITE_ROOT isnt set, this is looked for
+// next to the binary so it doesnt matter what directory the server gets
+// started in or which machine it was built on
+const DEFAULT_SITE_DIR: &str = "files";
+
 fn main() {
     let mut secrets = CREDS.lines();
     let username = secrets.next().unwrap();
@@ -43,232 +141,595 @@ fn main() {
         mail_api(r, clone)
     };
 
+    let site_root = find_site_root();
+    println!("Serving files from: {:?}", site_root);
+
+    let blog_dir = site_root.join("blog");
+    let recent_blog_dir = blog_dir.clone();
+    let recent_blog_posts = move |r: Request| -> Response {
+        get_recent_blog_posts(r, &recent_blog_dir)
+    };
+    let search_blog_dir = blog_dir;
+    let search_blog = move |r: Request| -> Response {
+        search_blog_posts(r, &search_blog_dir)
+    };
+
     let port = env::var("PORT").expect("Need PORT env var");
     let addr = String::from("0.0.0.0:") + &port;
     let listener = TcpListener::bind(addr).unwrap();
 
-    let pool = ThreadPool::new(8);
+    let pool = ThreadPool::with_config(PoolConfig {
+        min_workers: MIN_WORKERS,
+        max_workers: MAX_WORKERS,
+        max_queue: MAX_QUEUED_CONNECTIONS,
+        idle_timeout: WORKER_IDLE_TIMEOUT,
+    });
     let mut apis = ApiRegister::new();
     apis.register_api("/api/test", Box::new(test_api), 6, 360);
-    apis.register_api("/api/mail", Box::new(email_api), 6, 360);
-    apis.register_api("/api/recentBlogPosts", Box::new(get_recent_blog_posts), 60, 360);
-    apis.register_api("/api/searchBlog", Box::new(search_blog_posts), 20, 360);
+    apis.register_route(HTTPType::Post, "/api/mail", Box::new(email_api), 6, 360);
+    apis.register_route(HTTPType::Get, "/api/recentBlogPosts", Box::new(recent_blog_posts), 60, 360);
+    apis.register_route(HTTPType::Get, "/api/searchBlog", Box::new(search_blog), 20, 360);
     let apis = Arc::new(apis);
 
+    let mut mime_types = MimeRegistry::new();
+    // the blog post metadata made by blog_cli
+    mime_types.register("cbmd", ContentType::Other("application/x-cbmd"));
+
+    let not_found_page = site_root.join("404.html");
+    let mut router = Router::new();
+    router.set_mime_types(mime_types);
+    router.set_cors(CorsPolicy {
+        allowed_origins: cors_origins(),
+        ..CorsPolicy::default()
+    });
+    router.mount_api("/api", Arc::clone(&apis));
+    router.mount_dir("/examples", StaticDir::new(site_root.join("examples"))
+        .clean_urls(true)
+        .not_found(&not_found_page));
+    router.mount_dir("/", StaticDir::new(&site_root)
+        .index("index.html")
+        .clean_urls(true)
+        .not_found(&not_found_page));
+    let router = Arc::new(router);
+
+    if let Err(e) = shutdown::install_signal_handlers() {
+        println!("Error: {}, couldnt catch signals so shutting down wont be graceful", e);
+    }
+
     let register = Arc::clone(&apis);
-    let _cleaner = thread::spawn(|| {
+    let pool_stats = pool.stats();
+    // dropping stop_cleaner is what tells the cleaner to stop
+    let (stop_cleaner, cleaner_stop) = mpsc::channel::<()>();
+    let cleaner = thread::spawn(|| {
         // every 10mins will clear the registry of users (maybe should do it based on size?)
-        clean_api_register(register);
+        clean_api_register(register, pool_stats, cleaner_stop);
+    });
+
+    let full_queue = full_queue_policy();
+    #[cfg(all(target_os = "linux", feature = "epoll"))]
+    serve_events(listener, &pool, &router, full_queue);
+    #[cfg(not(all(target_os = "linux", feature = "epoll")))]
+    serve_threads(listener, &pool, &router, full_queue);
+
+    // the event loop may have already spent some of SHUTDOWN_TIMEOUT waiting
+    // on half sent requests, the pool only gets whats left of it
+    let deadline = shutdown::started().unwrap_or_else(Instant::now) + SHUTDOWN_TIMEOUT;
+    let time_left = deadline.saturating_duration_since(Instant::now());
+    println!("Shutting down, waiting up to {:?} for open connections", time_left);
+    let pool_stats = pool.stats();
+    let unfinished = pool.shutdown(time_left);
+
+    drop(stop_cleaner);
+    if cleaner.join().is_err() {
+        println!("The user cleaner panicked");
+    }
+
+    println!("connections dropped for being slow, {}", timeout::get_slow_drops());
+    println!("thread pool, {}", pool_stats);
+    match unfinished {
+        0 => println!("Shut down cleanly"),
+        _ => println!("Shut down with {} connections cut off", unfinished),
+    }
+}
+
+// SITE_ROOT or the files directory next to the binary, a server with nothing
+// to serve would only ever send 404s so this refuses to start instead
+fn find_site_root() -> PathBuf {
+    let site_root = match env::var_os("SITE_ROOT") {
+        Some(root) => PathBuf::from(root),
+        None => {
+            let exe = env::current_exe().expect("Couldnt find where the binary is, set SITE_ROOT instead");
+            exe.with_file_name(DEFAULT_SITE_DIR)
+        },
+    };
+    if !site_root.is_dir() {
+        panic!("Site root {:?} isnt a directory, set SITE_ROOT to where the files are", site_root);
+    }
+    site_root
+}
+
+// CORS_ORIGINS is a comma separated list like
+// 'https://a.example.com,https://b.example.com' of sites whose pages can call
+// the apis, without it only pages on this site can
+fn cors_origins() -> Vec<String> {
+    env::var("CORS_ORIGINS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|origin| !origin.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+// FULL_QUEUE=block waits for room instead of sending 503s, for when clients
+// would rather be slow than be turned away
+fn full_queue_policy() -> FullQueue {
+    match env::var("FULL_QUEUE") {
+        Ok(policy) if policy.eq_ignore_ascii_case("block") => FullQueue::Block,
+        _ => FullQueue::Reject,
+    }
+}
+
+// the event loop waits on every connection and only complete requests get
+// sent to the pool, so a slow client never holds a worker. Uploads are the
+// exception, theyre sent once the header is in and the body is read on the
+// worker with the same body timeout as everything else
+#[cfg(all(target_os = "linux", feature = "epoll"))]
+fn serve_events(listener: TcpListener, pool: &ThreadPool, router: &Arc<Router>, full_queue: FullQueue) {
+    let config = EventLoopConfig {
+        timeouts: TIMEOUTS,
+        limits: REQUEST_LIMITS,
+        max_connections: MAX_CONNECTIONS,
+        shutdown_timeout: SHUTDOWN_TIMEOUT,
+    };
+    let result = event_loop::run(listener, config, |connection| {
+        let router = router.clone();
+        match full_queue {
+            // this runs on the loop thread so it cant wait for room, the
+            // loop gets the connection back and stops accepting instead.
+            // Only the loop adds to the queue so execute wont block after
+            FullQueue::Block if pool.is_full() => return Some(connection),
+            FullQueue::Block => pool.execute(move || {
+                serve_request(connection, router)
+            }),
+            FullQueue::Reject => match pool.try_reserve() {
+                Some(slot) => slot.execute(move || {
+                    serve_request(connection, router)
+                }),
+                None => reject_overloaded(connection.into_stream()),
+            },
+        }
+        None
     });
+    if let Err(e) = result {
+        println!("Error: {}, the event loop stopped at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+    }
+}
 
-    for stream in listener.incoming() {
+// a connection per worker for as long as the client keeps it open
+#[cfg(not(all(target_os = "linux", feature = "epoll")))]
+fn serve_threads(listener: TcpListener, pool: &ThreadPool, router: &Arc<Router>, full_queue: FullQueue) {
+    while let Some(stream) = shutdown::next_connection(&listener) {
         match stream {
             Ok(stream) => {
-                let apis = apis.clone();
-                pool.execute(move || {
-                    handle_connection(stream, apis)
-                });
+                let router = router.clone();
+                match full_queue {
+                    FullQueue::Block => pool.execute(move || {
+                        handle_connection(stream, router)
+                    }),
+                    FullQueue::Reject => match pool.try_reserve() {
+                        Some(slot) => slot.execute(move || {
+                            handle_connection(stream, router)
+                        }),
+                        None => reject_overloaded(stream),
+                    },
+                }
             }
             Err(e) => println!("Error: {}, \n occured at: {}", e, turn_system_time_to_http_date(SystemTime::now())),
         }
 
     }
+    // closing the listener means new connections get refused straight away
+    // instead of sitting in the backlog until we exit
+    drop(listener);
 }
 
-fn handle_connection(mut stream: TcpStream, apis: Arc<ApiRegister>) {
-    let request = match Request::new(&mut stream) {
-        Ok(r) => r,
+// runs on the accepting thread so it cant wait on the client at all, if the
+// 503 doesnt fit in the socket buffer in one go the client just gets hung up on
+fn reject_overloaded(mut stream: TcpStream) {
+    if let Err(e) = stream.set_nonblocking(true) {
+        println!("Error: {}, occured at: {:?}", e, turn_system_time_to_http_date(SystemTime::now()));
+        return;
+    }
+
+    let response = Response::builder(StatusCode::ServiceUnavailable)
+        .header("Retry-After", &RETRY_AFTER_SECONDS.to_string())
+        .body(StatusCode::ServiceUnavailable.reason_phrase().as_bytes().to_vec())
+        .build();
+    if let Err(e) = response.write_to(&mut stream) {
+        log_write_error(e);
+    }
+}
+
+#[cfg(not(all(target_os = "linux", feature = "epoll")))]
+fn handle_connection(stream: TcpStream, router: Arc<Router>) {
+    if let Err(e) = Response::prepare_stream(&stream) {
+        println!("Error: {}, occured at: {:?}", e, turn_system_time_to_http_date(SystemTime::now()));
+    }
+
+    let stream = match TimedStream::new(stream, TIMEOUTS) {
+        Ok(stream) => stream,
         Err(e) => {
             println!("Error: {}, occured at: {:?}", e, turn_system_time_to_http_date(SystemTime::now()));
-            let response = Response::new_400_error(e).into_bytes();
-            stream.write_all(&response).unwrap_or_else(log_write_error);
+            return;
+        },
+    };
+
+    // the reader lives as long as the connection so any pipelined requests
+    // that got buffered with the last one are still there for the next loop
+    let mut reader = BufReader::new(stream);
+
+    for request_count in 1..=MAX_REQUESTS_PER_CONNECTION {
+        let pipelined = !reader.buffer().is_empty();
+        reader.get_mut().wait_for_request(pipelined);
+
+        let request = match Request::new(&mut reader, &REQUEST_LIMITS) {
+            Ok(r) => r,
+            Err(HTTPError::ConnectionClosed) => return,
+            Err(e) => {
+                send_request_error(e, reader.get_mut().get_mut());
+                return;
+            }
+        };
+
+        if !serve(request, request_count, &router, reader.get_mut().get_mut()) {
+            return;
+        }
+    }
+}
+
+// one request the event loop already has all of, the connection goes back
+// to the loop for the next one if its being kept alive
+#[cfg(all(target_os = "linux", feature = "epoll"))]
+fn serve_request(mut connection: Connection, router: Arc<Router>) {
+    let request = match connection.read_request(&REQUEST_LIMITS) {
+        Ok(r) => r,
+        Err(e) => {
+            send_request_error(e, connection.get_stream());
             return;
         }
     };
 
-    match request {
-        Request::GetRequest(_) => process_get_request(request, apis, &mut stream),
-        Request::POSTRequest(_) => process_post_request(request, apis, &mut stream)
+    let request_count = connection.get_request_count();
+    if serve(request, request_count, &router, connection.get_stream()) {
+        connection.keep_alive();
     }
 }
 
-fn process_get_request(request: Request, apis: Arc<ApiRegister>, stream: &mut TcpStream) {
-    let path = request.get_path();
-    println!("request_line: {:?}, {}", request, path == "/");
-    let path = Path::new(path);
-    let request_type = match path.parent().and_then(Path::to_str) {
-        Some("/") => {
-            if path == Path::new("/favicon.ico") || path.extension().is_some() {
-                RequestType::OtherFile
-            } else {
-                RequestType::Html
-            }
+// answers one request for either way of handling connections, gives back
+// whether the connection can be used for another request after
+fn serve(request: Request, request_count: usize, router: &Router, stream: &mut TcpStream) -> bool {
+    // connections get closed after the response once the server is
+    // shutting down so the workers can finish up
+    let keep_alive = request.wants_keep_alive()
+        && request_count < MAX_REQUESTS_PER_CONNECTION
+        && !shutdown::is_shutting_down();
+    let kind = request.get_kind();
+    let response = respond(request, router, keep_alive);
+    let keep_alive = response.will_keep_alive();
+
+    match write_response(response, kind, stream) {
+        Ok(()) => keep_alive,
+        Err(e) => {
+            log_write_error(e);
+            false
+        },
+    }
+}
+
+// for a request that couldnt be read, theres no telling where the next one
+// would start so the connection gets closed after this. A client that was
+// too slow still gets told with a 408
+fn send_request_error(error: HTTPError, stream: &mut TcpStream) {
+    println!("Error: {}, occured at: {:?}", error, turn_system_time_to_http_date(SystemTime::now()));
+    Response::new_error(error).write_to(stream).unwrap_or_else(log_write_error);
+}
+
+// runs the request through whatever handles it and gets the response ready
+// to send, keep_alive is whether the connection should stay open after
+fn respond(request: Request, router: &Router, keep_alive: bool) -> Response {
+    let kind = request.get_kind();
+    let version = request.get_version();
+    let encoding = request.preferred_encoding(&DYNAMIC_ENCODINGS);
+    let origin = router.cors().allowed_origin(&request).map(str::to_owned);
+    // a bug in one of the handlers shouldnt leave the client hanging, the
+    // request was already read in full so the connection is still good
+    let processed = panic::catch_unwind(AssertUnwindSafe(|| match kind {
+        HTTPType::Get | HTTPType::Head => process_get_request(request, router),
+        HTTPType::Options => process_options_request(request, router),
+        HTTPType::Post | HTTPType::Put | HTTPType::Delete | HTTPType::Patch => {
+            process_post_request(request, router)
+        },
+    }));
+    let mut response = match processed {
+        Ok(response) => response,
+        Err(_) => {
+            println!("Error: request handler panicked, occured at: {:?}", turn_system_time_to_http_date(SystemTime::now()));
+            Response::empty_500_error()
         },
-        Some("/api") => RequestType::Api,
-        None => RequestType::Html, // this is the index.html
-        // any other html file thats not in the html folder
-        Some(_) if path.extension().is_none() => RequestType::Html, 
-        Some(_) => RequestType::OtherFile,
     };
-    println!("{:?}, {:?}", path, request_type);
+    // a page on another site only gets to read the response if its allowed to
+    if let Some(origin) = origin {
+        response.set_header("Access-Control-Allow-Origin", &origin);
+        response.add_vary("Origin");
+    }
+    // api responses and error pages, files have already been taken care of
+    response.compress(encoding);
+    response.set_keep_alive(keep_alive);
+    response.set_version(version);
+    response
+}
 
-    match request_type {
-        RequestType::Html => html_request(path, stream),
-        RequestType::OtherFile => file_request(path, stream),
-        RequestType::Api => api_request(apis , stream, request),
+// HEAD gets exactly what GET would have gotten just without the body
+fn write_response(response: Response, kind: HTTPType, stream: &mut TcpStream) -> Result<(), io::Error> {
+    match kind {
+        HTTPType::Head => response.write_head_to(stream),
+        _ => response.write_to(stream),
     }
 }
 
-fn process_post_request(request: Request, apis: Arc<ApiRegister>, stream: &mut TcpStream) {
-    println!("post!, {:?}", request);
-    // should therortically just be an API request
+fn process_get_request(request: Request, router: &Router) -> Response {
+    let route = router.route(request.get_path());
+    println!("{:?}, {:?}", request.get_path(), route);
 
-    match Path::new(request.get_path()).parent().and_then(Path::to_str) {
-        Some("/api") => {},
-        Some(_) => {
-            // honeslty not sure what error code belongs here
-            let response = Response::empty_404().into_bytes();
-            stream.write_all(&response).unwrap_or_else(log_write_error);
-            return;
+    match route {
+        Route::File(path) => file_request(&request, &path, router.mime_types()),
+        Route::Listing(dir) => match render_listing(request.get_path(), &dir) {
+            Ok(page) => Response::new_ok(ContentType::Html, None, page.into_bytes()),
+            Err(e) => {
+                println!("Error: {}\n Occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+                Response::empty_500_error()
+            },
+        },
+        Route::Redirect(location) => Response::builder(StatusCode::MovedPermanently)
+            .header("Location", &location)
+            .build(),
+        Route::NotFound(page) => not_found(page.as_deref()),
+        Route::Api(apis) => api_request(apis, request, router.cors()),
+    }
+}
+
+// handles POST, PUT, DELETE and PATCH
+fn process_post_request(request: Request, router: &Router) -> Response {
+    println!("{}!, {:?}", request.get_kind(), request);
+    // only APIs can take anything other than a GET
+    match router.route(request.get_path()) {
+        Route::Api(apis) => api_request(apis, request, router.cors()),
+        Route::NotFound(_) => Response::empty_404(),
+        _ => Response::new_405_error(STATIC_FILE_METHODS),
+    }
+}
+
+fn process_options_request(request: Request, router: &Router) -> Response {
+    // APIs know what methods they take so let them answer for themselves
+    // everything else is a plain file which can only be read
+    match router.route(request.get_path()) {
+        Route::Api(apis) => api_request(apis, request, router.cors()),
+        _ => Response::new_preflight(&request, STATIC_FILE_METHODS, router.cors()),
+    }
+}
+
+// sends the mounts 404 page if it has one and it can be read
+fn not_found(page: Option<&Path>) -> Response {
+    let page = match page {
+        Some(page) => page,
+        None => return Response::empty_404(),
+    };
+
+    let data = match fs::read(page) {
+        Ok(data) => data,
+        Err(e) => {
+            println!("Error: {}\n Occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+            return Response::empty_404();
         }
+    };
+    let modified_date = page.metadata().and_then(into_modified).ok();
+
+    Response::new(StatusCode::NotFound, ContentType::Html, modified_date, None, data)
+}
+
+fn file_request(request: &Request, path: &Path, mime_types: &MimeRegistry) -> Response {
+    let content_type = match mime_types.lookup(path) {
+        Some(content_type) => content_type,
         None => {
-            // errors
-            let response = Response::empty_404().into_bytes();
-            stream.write_all(&response).unwrap_or_else(log_write_error);
-            return;
+            println!("Unsuported extention: {:?}", path.extension());
+            return Response::new_400_error(HTTPError::InvalidPath);
         }
     };
 
-    api_request(apis, stream, request);
-}
+    println!("{:?}", path);
 
-fn html_request(path: &Path, stream: &mut TcpStream) {
-    if path.as_os_str() == "/" {
-        let index_path = Path::new("website/files/index.html");
-        let data = fs::read(index_path).unwrap();
-        let last_modified = match index_path.metadata().and_then(into_modified) {
-            Ok(time) => Some(time),
-            Err(_) => None,
-        };
-        let response = Response::new_ok(ContentType::Html, last_modified, data)
-            .into_bytes();
-        stream.write_all(&response).unwrap_or_else(log_write_error);
-        return;
+    match read_static_file(request, path, content_type) {
+        Some(response) => response,
+        None => Response::empty_404(),
     }
-    // I Hate paths dear lord wtf is this garbage
-    let path = Path::new("website/files").join(path.strip_prefix("/").unwrap()).with_extension("html");
-    println!("{:?}", path.as_path());
-
-    match fs::read(&path) {
-        Ok(data) => {
-            let last_modified = match path.metadata().and_then(into_modified) {
-                Ok(time) => Some(time),
-                Err(_) => None,
-            };
-            let response = Response::new_ok(ContentType::Html, last_modified, data)
-                .into_bytes();
-            stream.write_all(&response).unwrap_or_else(log_write_error);
-        },
-        Err(_) => {
-            let data = match fs::read("website/files/404.html") {
-                Ok(data) => data,
-                Err(e) => {
-                    println!("Error: {}\n Occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
-                    let response = Response::empty_500_error().into_bytes();
-                    stream.write_all(&response).unwrap_or_else(log_write_error);
-                    return;
-                }
-            };
+}
 
-            let modified_date = match Path::new("files/404.html").metadata().and_then(into_modified) {
-                Ok(m) => Some(m),
-                Err(_) => None,
-            };
+// reads a file off of disk unless the client already has the latest version
+// of it in which case they just get a 304, None if the file cant be read
+fn read_static_file(request: &Request, path: &Path, content_type: ContentType) -> Option<Response> {
+    let metadata = path.metadata().ok()?;
+    let last_modified = metadata.modified().ok();
 
-            let response = Response::new(404, ContentType::Html, modified_date, None, data)
-                .into_bytes();
-            stream.write_all(&response).unwrap_or_else(log_write_error)
+    // text like files get sent compressed when the client can take it, either from
+    // a copy compressed at build time (foo.wasm.br) or compressed right now
+    let mut available = Vec::new();
+    let mut precompressed = Vec::new();
+    if content_type.is_compressible() {
+        for encoding in PRECOMPRESSED_ENCODINGS {
+            if let Some((sibling, sibling_metadata)) = find_precompressed(path, &metadata, encoding) {
+                available.push(encoding);
+                precompressed.push((encoding, sibling, sibling_metadata));
+            }
         }
-    }
-}
 
-fn file_request(path: &Path, stream: &mut TcpStream) {
-    let content_type = match path.extension().and_then(OsStr::to_str) {
-        Some("css") => ContentType::Css,
-        Some("js") => ContentType::JavaScript,
-        Some("png") => ContentType::Image(ImageType::Png),
-        Some("svg") => ContentType::Image(ImageType::Svg),
-        Some("ico") => ContentType::Image(ImageType::XIcon),
-        Some("ttf") => ContentType::Font(FontType::Ttf),
-        Some("wasm") => ContentType::Wasm,
-        Some("wgsl") => ContentType::Wgsl,
-        ext => {
-            println!("Unsuported extention: {:?}", ext);
-            let response = Response::new_400_error(HTTPError::InvalidPath).into_bytes();
-            stream.write_all(&response).unwrap_or_else(log_write_error);
-            return;
+        // a Range of something compressed on the fly would mean compressing the whole
+        // thing every time just to send a bit of it so those just get the plain file
+        let length = metadata.len();
+        let worth_compressing = length >= MIN_COMPRESS_SIZE as u64 && length <= MAX_DYNAMIC_COMPRESS_SIZE;
+        if worth_compressing && !request.headers().contains("Range") {
+            for encoding in DYNAMIC_ENCODINGS {
+                if !available.contains(&encoding) {
+                    available.push(encoding);
+                }
+            }
         }
+    }
+    let encoding = request.preferred_encoding(&available);
+
+    let (path, metadata, compress_now) = match precompressed.into_iter().find(|(e, _, _)| *e == encoding) {
+        Some((_, sibling, sibling_metadata)) => (sibling, sibling_metadata, false),
+        None => (path.to_path_buf(), metadata, encoding != Encoding::Identity),
     };
+    let etag = make_etag(&metadata).map(|etag| encoded_etag(&etag, encoding));
+
+    if request.is_not_modified(etag.as_deref(), last_modified) {
+        let mut response = Response::new_not_modified(last_modified, etag);
+        if content_type.is_compressible() {
+            response.set_content_encoding(encoding);
+        }
+        return Some(response);
+    }
 
-    // paths will single handly kill me
-    // also we know path stripping wont fail bc we make sure it starts with one
-    let path = Path::new("website/files").join(path.strip_prefix("/").unwrap());
-    let modified_date = match path.metadata().and_then(into_modified) {
-        Ok(time) => Some(time),
-        Err(_) => None,
+    let length = metadata.len();
+    let ranges = match request.if_range_matches(etag.as_deref(), last_modified) {
+        true => request.get_byte_ranges(length),
+        false => ByteRanges::Full,
     };
 
-    println!("{:?}", path);
+    let mut response = match ranges {
+        ByteRanges::Full if compress_now => {
+            let data = compression::compress(&fs::read(&path).ok()?, encoding).ok()?;
+            Response::new_ok(content_type, last_modified, data)
+        },
+        ByteRanges::Full => {
+            let file = File::open(path).ok()?;
+            Response::new_ok(content_type, last_modified, Body::from_file(file).ok()?)
+        },
+        ByteRanges::Unsatisfiable => Response::new_range_not_satisfiable(length),
+        ByteRanges::Partial(ranges) => {
+            let file = File::open(path).ok()?;
+            match ranges.as_slice() {
+                [(start, end)] => {
+                    let body = Body::File {
+                        file,
+                        offset: *start,
+                        length: end - start + 1,
+                    };
+                    let content_range = format!("bytes {}-{}/{}", start, end, length);
+                    Response::new_partial(content_type, last_modified, Some(content_range), body)
+                },
+                _ => {
+                    // more than one range gets sent as a multipart body each with
+                    // its own little header saying which part it is
+                    let mut parts = Vec::new();
+                    for (start, end) in ranges {
+                        let part_header = format!(
+                            "--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                            BYTERANGES_BOUNDARY, content_type, start, end, length
+                        );
+                        parts.push(FilePart::Text(part_header.into_bytes()));
+                        parts.push(FilePart::Range(start, end - start + 1));
+                        parts.push(FilePart::Text(b"\r\n".to_vec()));
+                    }
+                    parts.push(FilePart::Text(format!("--{}--\r\n", BYTERANGES_BOUNDARY).into_bytes()));
 
-    match fs::read(path) {
-        Ok(data) => {
-            let response = Response::new_ok(content_type, modified_date, data)
-                .into_bytes();
-            stream.write_all(&response).unwrap_or_else(log_write_error)
+                    let parts = FileParts::new(file, parts);
+                    let body = Body::Stream {
+                        length: parts.len(),
+                        chunks: Box::new(parts),
+                    };
+                    Response::new_partial(ContentType::MultipartByteRanges, last_modified, None, body)
+                },
+            }
         },
-        Err(_) => {
-            let response = Response::empty_404().into_bytes();
-            stream.write_all(&response).unwrap_or_else(log_write_error)
-        }
+    };
+
+    if let Some(etag) = etag {
+        response.set_etag(&etag);
     }
+    if content_type.is_compressible() {
+        response.set_content_encoding(encoding);
+    }
+    response.set_accept_ranges(true);
+
+    Some(response)
+}
+
+// foo.wasm.br next to foo.wasm, ignored if its older than the file it came
+// from as that means someone forgot to recompress it after changing things
+fn find_precompressed(path: &Path, metadata: &Metadata, encoding: Encoding) -> Option<(PathBuf, Metadata)> {
+    let extension = encoding.file_extension()?;
+    let mut sibling = path.as_os_str().to_owned();
+    sibling.push(".");
+    sibling.push(extension);
+    let sibling = PathBuf::from(sibling);
+
+    let sibling_metadata = sibling.metadata().ok()?;
+    if !sibling_metadata.is_file() || sibling_metadata.modified().ok()? < metadata.modified().ok()? {
+        return None;
+    }
+
+    Some((sibling, sibling_metadata))
+}
+
+// a strong etag made from the modified time and size, way cheaper than hashing
+// the whole file every request and still changes whenever the file does
+fn make_etag(metadata: &Metadata) -> Option<String> {
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(format!("\"{:x}-{:x}\"", modified.as_nanos(), metadata.len()))
 }
 
 fn log_write_error(error: std::io::Error) {
+    // the client stopped reading what we were sending it
+    if timeout::is_timeout(&error) {
+        timeout::record_slow_drop(SlowStep::Write);
+    }
     let time = turn_system_time_to_http_date(SystemTime::now());
     println!("\nError sending response: {error}, occured at: {time}\n")
 }
 
 
-fn api_request(apis: Arc<ApiRegister>, stream: &mut TcpStream, request: Request) {
-    let path = request.get_path();
+fn api_request(apis: &ApiRegister, mut request: Request, cors: &CorsPolicy) -> Response {
     // check if the user is over the limit
     if !apis.user_exists(&request.get_ip()) {
         apis.add_user(request.get_ip());
     }
 
-    if !apis.check_limit(&request.get_ip(), request.get_path()) {
+    // limits are kept per pattern so '/api/blog/1' and '/api/blog/2' share one
+    let api = apis.get_api(request.get_path());
+    let limit_key = match &api {
+        Some(api) => api.pattern,
+        None => request.get_path(),
+    };
+
+    if !apis.check_limit(&request.get_ip(), limit_key) {
         // too many requests
         let data = String::from("Too many requests").into_bytes();
-        let response = Response::new(429, ContentType::PlainText, None, None, data)
-            .into_bytes();
-
-        stream.write_all(&response).unwrap_or_else(log_write_error);
-        return;
+        return Response::new(StatusCode::TooManyRequests, ContentType::PlainText, None, None, data);
     }
 
-    let api = apis.get_api(path);
-    let response = match api {
+    match api {
         None => {
             apis.add_gloabal_request(request.get_ip());
             Response::empty_404()
         },
         Some(api) => {
-            apis.add_request(request.get_path(), request.get_ip());
-            api.run(request)
+            apis.add_request(api.pattern, request.get_ip());
+            request.set_params(api.params);
+            api.api.run(request, cors)
         },
-    };
-
-    stream.write_all(&response.into_bytes()).unwrap_or_else(log_write_error);
+    }
 }
 
 // made to use and_then on results for reading meta data to avoid unsessicary unwrap
@@ -276,53 +737,51 @@ fn into_modified(metadata: Metadata) -> Result<SystemTime, std::io::Error> {
     metadata.modified()
 }
 
-fn clean_api_register(register: Arc<ApiRegister>) -> ! {
+fn clean_api_register(register: Arc<ApiRegister>, pool_stats: Arc<PoolStats>, stop: Receiver<()>) {
     loop {
-        thread::sleep(Duration::from_secs(1200));
+        // sleeps the same way but wakes up as soon as the server shuts down
+        match stop.recv_timeout(Duration::from_secs(1200)) {
+            Err(RecvTimeoutError::Timeout) => {},
+            _ => return,
+        }
         println!("cleaning users...");
         register.clean_recent_requests();
         println!("done cleaning users!");
+        println!("connections dropped for being slow so far, {}", timeout::get_slow_drops());
+        println!("thread pool so far, {}", pool_stats);
     }
 }
 
-fn test_api(_: Request) -> Response {
+fn test_api(request: Request) -> Response {
+    if request.get_kind() == HTTPType::Options {
+        return Response::new_options("GET, HEAD, POST, PUT, DELETE, OPTIONS, PATCH");
+    }
+
     println!("Test Api!");
     let data = String::from("Test api!").into_bytes();
     Response::new_ok(ContentType::PlainText, None, data)
 }
 
-// takes ~1.6 seconds to send both emails and send a response
-// ~675ms per email so might async or do something to speed this up
-// maybe multithread each email (this is a joke)
-fn mail_api(request: Request, mailer: Arc<SmtpTransport>) -> Response {
-    let request = match request {
-        Request::GetRequest(_) => {
-            let res = Response::new_405_error("POST");
-            return res;
-        }, //405 error
-        Request::POSTRequest(r) => r,
-    };
-
-    match request.get_content_type() {
-        ContentType::OctetStream => {},
-        _ => {
-            let data = String::from("Unssuported Media Type").into_bytes();
-            return Response::new(415, ContentType::PlainText, None, None, data)
-        }
-    }
+#[derive(Deserialize)]
+struct MailRequest {
+    email: String,
+    message: String,
+}
 
-    let mut data = BufReader::new(request.get_data());
+// email length (u8), email, message length (u16 le), message
+fn read_binary_mail(data: &[u8]) -> Result<(String, String), Response> {
+    let mut data = BufReader::new(data);
     let mut email_len = [0_u8; 1];
 
     match data.read_exact(&mut email_len) {
         Err(_) => {
-            return Response::new(
-                400,
+            return Err(Response::new(
+                StatusCode::BadRequest,
                 ContentType::PlainText,
                 None,
                 None,
                 String::from("Email Length Not Found").into_bytes()
-            );
+            ));
         }
         Ok(_) => {},
     }
@@ -331,13 +790,13 @@ fn mail_api(request: Request, mailer: Arc<SmtpTransport>) -> Response {
 
     match data.read_exact(&mut email) {
         Err(_) => {
-            return Response::new(
-                400,
+            return Err(Response::new(
+                StatusCode::BadRequest,
                 ContentType::PlainText,
                 None,
                 None,
                 String::from("Email Not Found").into_bytes()
-            );
+            ));
         }
         Ok(_) => {},
     }
@@ -346,13 +805,13 @@ fn mail_api(request: Request, mailer: Arc<SmtpTransport>) -> Response {
 
     match data.read_exact(&mut message_len) {
         Err(_) => {
-            return Response::new(
-                400,
+            return Err(Response::new(
+                StatusCode::BadRequest,
                 ContentType::PlainText,
                 None,
                 None,
                 String::from("Message Length Not Found").into_bytes()
-            );
+            ));
         }
         Ok(_) => {},
     }
@@ -361,25 +820,62 @@ fn mail_api(request: Request, mailer: Arc<SmtpTransport>) -> Response {
     let mut message = vec![0_u8; message_len];
     match data.read_exact(&mut message) {
         Err(_) => {
-            return Response::new(
-                400,
+            return Err(Response::new(
+                StatusCode::BadRequest,
                 ContentType::PlainText,
                 None,
                 None,
                 String::from("Message Not Found").into_bytes()
-            );
+            ));
         }
         Ok(_) => {},
     }
 
-    // send the email!
-    let user_email = String::from_utf8_lossy(&email);
-    let user_message = String::from_utf8_lossy(&message); 
+    let user_email = String::from_utf8_lossy(&email).to_string();
+    let user_message = String::from_utf8_lossy(&message).to_string();
+    Ok((user_email, user_message))
+}
+
+// takes ~1.6 seconds to send both emails and send a response
+// ~675ms per email so might async or do something to speed this up
+// maybe multithread each email (this is a joke)
+fn mail_api(request: Request, mailer: Arc<SmtpTransport>) -> Response {
+    // the contact form sends json, the old binary format still works too
+    let (user_email, user_message) = match request.get_content_type() {
+        ContentType::Json => match request.json::<MailRequest>() {
+            Ok(mail) => (mail.email, mail.message),
+            Err(e) => return Response::new_400_error(e),
+        },
+        ContentType::OctetStream => match read_binary_mail(request.get_data()) {
+            Ok(mail) => mail,
+            Err(response) => return response,
+        },
+        // a plain html form with no javascript
+        ContentType::FormUrlEncoded => match request.get_form() {
+            Ok(form) => match (form.get("email"), form.get("message")) {
+                (Some(email), Some(message)) => (email.to_owned(), message.to_owned()),
+                _ => return Response::new_400_error(HTTPError::InvalidContent),
+            },
+            Err(e) => return Response::new_400_error(e),
+        },
+        ContentType::MultipartFormData => match request.get_multipart() {
+            Ok(form) => match (form.text("email"), form.text("message")) {
+                (Some(email), Some(message)) => (email.to_owned(), message.to_owned()),
+                _ => return Response::new_400_error(HTTPError::InvalidContent),
+            },
+            Err(e) => return Response::new_error(e),
+        },
+        _ => {
+            let data = String::from("Unssuported Media Type").into_bytes();
+            return Response::new(StatusCode::UnsupportedMediaType, ContentType::PlainText, None, None, data)
+        }
+    };
 
     if user_message.len() == 0 {
         return Response::new_400_error(HTTPError::InvalidContentLength);
     }
 
+    // send the email!
     let message_to_self = format!("contacter email: {user_email},\n\n{user_message}");
     let send_to = CREDS.lines().next().unwrap();
     let self_mailbox: Mailbox = format!("Charlie Crabtree <{send_to}>").parse().unwrap();
@@ -411,12 +907,7 @@ fn mail_api(request: Request, mailer: Arc<SmtpTransport>) -> Response {
     Response::empty_ok()
 }
 
-fn get_recent_blog_posts(request: Request) -> Response {
-    let request = match request {
-        Request::GetRequest(r) => r,
-        Request::POSTRequest(_) => return Response::new_405_error("GET"),
-    };
-
+fn get_recent_blog_posts(request: Request, blog_dir: &Path) -> Response {
     let skip = match request.get_query("skip") {
         None => 0,
         Some(value) => match value.parse::<usize>() {
@@ -440,36 +931,80 @@ fn get_recent_blog_posts(request: Request) -> Response {
     };
 
     //read in cbmd
-    let dir = fs::read_dir("website/files/blog").unwrap();
+    let dir = match fs::read_dir(blog_dir) {
+        Ok(dir) => dir,
+        Err(e) => {
+            println!("Error: {}\n Occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+            return Response::empty_500_error();
+        }
+    };
     let mut blog_data = dir.filter_map(|f| f.ok())
         .filter(|f| f.path().extension() == Some(OsStr::new("cbmd")))
         .filter_map(|f| Cbmd::from_meta_file(&f.path()).ok())
         .collect::<Vec<Cbmd>>();
     blog_data.sort_by(|a, b| b.get_timestamp().cmp(&a.get_timestamp()));
     
-    send_blog_vec(blog_data, skip, max)
+    send_blog_posts(&request, blog_data, skip, max)
 }
 
-fn search_blog_posts(request: Request) -> Response {
-    let request = match request {
-        Request::GetRequest(r) => r,
-        Request::POSTRequest(_) => return Response::new_405_error("GET"),
-    };
-
+fn search_blog_posts(request: Request, blog_dir: &Path) -> Response {
     let blog_title = match request.get_query("title") {
-        Some(t) => clean_url_spaces(t),
+        Some(t) => t.to_owned(),
         None => return Response::new_400_error(HTTPError::InvalidPath),
     };
 
     //read in cbmd
-    let dir = fs::read_dir("website/files/blog").unwrap();
+    let dir = match fs::read_dir(blog_dir) {
+        Ok(dir) => dir,
+        Err(e) => {
+            println!("Error: {}\n Occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+            return Response::empty_500_error();
+        }
+    };
     let blog_data = dir.filter_map(|f| f.ok())
         .filter(|f| f.path().extension() == Some(OsStr::new("cbmd")))
         .filter_map(|f| Cbmd::from_meta_file(&f.path()).ok())
         .filter(|f| f.get_title().contains(&blog_title))
         .collect::<Vec<Cbmd>>();
 
-    send_blog_vec(blog_data, 0, 8)
+    send_blog_posts(&request, blog_data, 0, 8)
+}
+
+// the binary format is what the frontend already decodes by hand so its
+// what gets sent unless the client asks for json
+fn send_blog_posts(request: &Request, data: Vec<Cbmd>, skip: usize, max: usize) -> Response {
+    let mut response = match request.preferred_content_type(&BLOG_POST_CONTENT_TYPES) {
+        Some(ContentType::Json) => send_blog_json(data, skip, max),
+        Some(_) => send_blog_vec(data, skip, max),
+        None => Response::new_status(StatusCode::NotAcceptable),
+    };
+    response.add_vary("Accept");
+    response
+}
+
+#[derive(Serialize)]
+struct BlogPost<'a> {
+    title: &'a str,
+    intro_words: &'a str,
+    path: &'a str,
+    publish_ts: u64,
+    date: String,
+}
+
+fn send_blog_json(data: Vec<Cbmd>, skip: usize, max: usize) -> Response {
+    let posts = data.iter()
+        .skip(skip)
+        .take(max)
+        .map(|post| BlogPost {
+            title: post.get_title(),
+            intro_words: post.get_intro_words(),
+            path: post.get_path(),
+            publish_ts: post.get_timestamp(),
+            date: post.format_date(),
+        })
+        .collect::<Vec<BlogPost>>();
+
+    Response::json(&posts)
 }
 
 fn send_blog_vec(data: Vec<Cbmd>, skip: usize, max: usize) -> Response {
@@ -492,9 +1027,232 @@ fn send_blog_vec(data: Vec<Cbmd>, skip: usize, max: usize) -> Response {
         data.extend_from_slice(&byte_array);
     }
 
-    Response::new(200, ContentType::OctetStream, None, None, data)
+    Response::new(StatusCode::Ok, ContentType::OctetStream, None, None, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a file in a directory of its own, removed again when dropped
+    struct TestFile {
+        dir: PathBuf,
+        path: PathBuf,
+    }
+
+    impl TestFile {
+        fn new(test: &str, name: &str, contents: &[u8]) -> Self {
+            let dir = env::temp_dir().join(format!("main-test-{}-{}", test, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            let path = dir.join(name);
+            fs::write(&path, contents).unwrap();
+            Self { dir, path }
+        }
+    }
+
+    impl Drop for TestFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn request(headers: &str) -> Request {
+        let raw = format!("GET /file HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\n{}\r\n", headers);
+        Request::new(&mut raw.as_bytes(), &REQUEST_LIMITS).unwrap()
+    }
+
+    fn static_file(file: &TestFile, headers: &str) -> Response {
+        read_static_file(&request(headers), &file.path, ContentType::PlainText).unwrap()
+    }
+
+    // everything the response puts on the wire, over a real socket as thats
+    // the only thing responses can be written to
+    fn sent(response: Response) -> Vec<u8> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        response.write_to(&mut server).unwrap();
+        drop(server);
+
+        let mut sent = Vec::new();
+        client.read_to_end(&mut sent).unwrap();
+        sent
+    }
+
+    fn body(response: Response) -> Vec<u8> {
+        let sent = sent(response);
+        let start = sent.windows(4).position(|window| window == b"\r\n\r\n").unwrap() + 4;
+        sent[start..].to_vec()
+    }
+
+    #[test]
+    fn unchanged_files_get_a_304() {
+        let file = TestFile::new("conditional", "notes.txt", b"some notes");
+        let first = static_file(&file, "");
+        assert_eq!(first.get_code(), StatusCode::Ok);
+        let etag = first.header("ETag").unwrap().to_owned();
+        let last_modified = first.header("Last-Modified").unwrap().to_owned();
+
+        let cached = static_file(&file, &format!("If-None-Match: {}\r\n", etag));
+        assert_eq!(cached.get_code(), StatusCode::NotModified);
+        assert_eq!(cached.header("ETag"), Some(etag.as_str()));
+        assert!(body(cached).is_empty());
+
+        let cached = static_file(&file, &format!("If-Modified-Since: {}\r\n", last_modified));
+        assert_eq!(cached.get_code(), StatusCode::NotModified);
+    }
+
+    #[test]
+    fn changed_files_are_sent_again() {
+        let file = TestFile::new("changed", "notes.txt", b"some notes");
+        let response = static_file(&file, "If-None-Match: \"something-else\"\r\n");
+        assert_eq!(response.get_code(), StatusCode::Ok);
+        assert_eq!(body(response), b"some notes");
+
+        let response = static_file(&file, "If-Modified-Since: Thu, 01 Jan 1970 00:00:01 GMT\r\n");
+        assert_eq!(response.get_code(), StatusCode::Ok);
+    }
+
+    #[test]
+    fn one_range_gets_a_206() {
+        let file = TestFile::new("range", "digits.txt", b"0123456789");
+        let response = static_file(&file, "Range: bytes=2-5\r\n");
+        assert_eq!(response.get_code(), StatusCode::PartialContent);
+        assert_eq!(response.header("Content-Range"), Some("bytes 2-5/10"));
+        assert_eq!(response.header("Accept-Ranges"), Some("bytes"));
+        assert_eq!(body(response), b"2345");
+    }
+
+    #[test]
+    fn ranges_past_the_end_get_a_416() {
+        let file = TestFile::new("unsatisfiable", "digits.txt", b"0123456789");
+        let response = static_file(&file, "Range: bytes=10-20\r\n");
+        assert_eq!(response.get_code(), StatusCode::RangeNotSatisfiable);
+        assert_eq!(response.header("Content-Range"), Some("bytes */10"));
+    }
+
+    #[test]
+    fn many_ranges_get_a_multipart_body() {
+        let file = TestFile::new("byteranges", "digits.txt", b"0123456789");
+        let response = static_file(&file, "Range: bytes=0-1, 8-\r\n");
+        assert_eq!(response.get_code(), StatusCode::PartialContent);
+        assert_eq!(response.header("Content-Range"), None);
+
+        let sent = String::from_utf8(sent(response)).unwrap();
+        assert!(sent.contains(&format!("Content-type: multipart/byteranges; boundary={}\r\n", BYTERANGES_BOUNDARY)));
+        let expected = format!(
+            "--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\r\n--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 8-9/10\r\n\r\n89\r\n--{b}--\r\n",
+            b = BYTERANGES_BOUNDARY
+        );
+        assert!(sent.ends_with(&expected), "{}", sent);
+        assert!(sent.contains(&format!("Content-length: {}\r\n", expected.len())));
+    }
+
+    #[test]
+    fn a_stale_if_range_gets_the_whole_file() {
+        let file = TestFile::new("if-range", "digits.txt", b"0123456789");
+        let response = static_file(&file, "Range: bytes=2-5\r\nIf-Range: \"stale\"\r\n");
+        assert_eq!(response.get_code(), StatusCode::Ok);
+        assert_eq!(body(response), b"0123456789");
+    }
+
+    #[test]
+    fn precompressed_files_are_used_when_the_client_takes_them() {
+        let file = TestFile::new("precompressed", "app.js", b"console.log('plain')");
+        let compressed = compression::compress(b"console.log('plain')", Encoding::Brotli).unwrap();
+        fs::write(file.dir.join("app.js.br"), &compressed).unwrap();
+        let read = |headers: &str| read_static_file(&request(headers), &file.path, ContentType::JavaScript).unwrap();
+
+        let response = read("Accept-Encoding: gzip, br\r\n");
+        assert_eq!(response.header("Content-Encoding"), Some("br"));
+        assert!(response.header("ETag").unwrap().ends_with("-br\""));
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+        assert_eq!(body(response), compressed);
+
+        // too small to compress on the fly and theres no .gz
+        let response = read("Accept-Encoding: gzip\r\n");
+        assert_eq!(response.header("Content-Encoding"), None);
+        assert_eq!(body(response), b"console.log('plain')");
+    }
+
+    #[test]
+    fn big_text_files_are_compressed_on_the_fly() {
+        let text = "let x = 1;\n".repeat(500);
+        let file = TestFile::new("dynamic", "big.js", text.as_bytes());
+        let response = read_static_file(&request("Accept-Encoding: gzip\r\n"), &file.path, ContentType::JavaScript).unwrap();
+        assert_eq!(response.header("Content-Encoding"), Some("gzip"));
+        let compressed = body(response);
+        assert!(compressed.len() < text.len());
+
+        // a range of it has to come from the plain file
+        let response = read_static_file(&request("Accept-Encoding: gzip\r\nRange: bytes=0-9\r\n"), &file.path, ContentType::JavaScript).unwrap();
+        assert_eq!(response.header("Content-Encoding"), None);
+        assert_eq!(body(response), b"let x = 1;");
+    }
+
+    #[test]
+    fn blog_posts_are_sent_as_json_when_asked_for() {
+        let json = send_blog_posts(&request("Accept: application/json\r\n"), Vec::new(), 0, 8);
+        assert_eq!(json.get_code(), StatusCode::Ok);
+        assert_eq!(json.header("Vary"), Some("Accept"));
+        let wire = String::from_utf8(sent(json)).unwrap();
+        assert!(wire.contains("Content-type: application/json\r\n"));
+        assert!(wire.ends_with("\r\n\r\n[]"));
+
+        let binary = send_blog_posts(&request(""), Vec::new(), 0, 8);
+        assert!(String::from_utf8_lossy(&sent(binary)).contains("Content-type: application/octet-stream\r\n"));
+
+        let refused = send_blog_posts(&request("Accept: text/html\r\n"), Vec::new(), 0, 8);
+        assert_eq!(refused.get_code(), StatusCode::NotAcceptable);
+        assert_eq!(refused.header("Vary"), Some("Accept"));
+    }
+
+    #[test]
+    fn files_are_sent_as_their_mime_type() {
+        let file = TestFile::new("mime", "data.unknownext", b"\x00\x01");
+        let wire = sent(file_request(&request(""), &file.path, &MimeRegistry::new()));
+        assert!(String::from_utf8_lossy(&wire).contains("Content-type: application/octet-stream\r\n"));
+
+        // without a fallback unknown files are refused
+        let mut strict = MimeRegistry::new();
+        strict.set_fallback(None);
+        assert_eq!(file_request(&request(""), &file.path, &strict).get_code(), StatusCode::BadRequest);
+
+        let mut custom = MimeRegistry::new();
+        custom.register("unknownext", ContentType::Other("application/x-custom"));
+        let wire = sent(file_request(&request(""), &file.path, &custom));
+        assert!(String::from_utf8_lossy(&wire).contains("Content-type: application/x-custom\r\n"));
+    }
+
+    #[test]
+    fn a_panicking_handler_gets_a_500() {
+        let mut apis = ApiRegister::new();
+        apis.register_route(HTTPType::Get, "/api/boom", Box::new(|_| panic!("handler went wrong")), 10, 60);
+        let mut router = Router::new();
+        router.mount_api("/api", Arc::new(apis));
+
+        let raw = "GET /api/boom HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\n\r\n";
+        let request = Request::new(&mut raw.as_bytes(), &REQUEST_LIMITS).unwrap();
+        let response = respond(request, &router, true);
+        assert_eq!(response.get_code(), StatusCode::InternalServerError);
+        // the request was read in full so the connection is still good
+        assert!(response.will_keep_alive());
+    }
+
+    #[test]
+    fn overloaded_connections_get_a_503_with_retry_after() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        reject_overloaded(server);
+
+        let mut sent = Vec::new();
+        client.read_to_end(&mut sent).unwrap();
+        let sent = String::from_utf8(sent).unwrap();
+        assert!(sent.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(sent.contains(&format!("\r\nRetry-After: {}\r\n", RETRY_AFTER_SECONDS)));
+        assert!(sent.ends_with("\r\n\r\nService Unavailable"));
+    }
 }
 
-fn clean_url_spaces(text: &str) -> String {
-    text.replace("%20", " ")
-}
\ No newline at end of file
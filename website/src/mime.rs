@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+use crate::types::{ContentType, FontType, HTTPError, ImageType};
+
+// a media type like 'text/plain; charset=utf-8' split into its parts.
+// the type, subtype and parameter names are case insensitive so they are
+// kept lowercase, parameter values are kept as they were sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mime {
+    main_type: String,
+    subtype: String,
+    params: Vec<(String, String)>,
+}
+
+impl Mime {
+    pub fn new(main_type: &str, subtype: &str) -> Self {
+        Self {
+            main_type: main_type.to_ascii_lowercase(),
+            subtype: subtype.to_ascii_lowercase(),
+            params: Vec::new(),
+        }
+    }
+
+    pub fn with_param(mut self, name: &str, value: &str) -> Self {
+        self.params.push((name.to_ascii_lowercase(), value.to_owned()));
+        self
+    }
+
+    pub fn get_type(&self) -> &str {
+        &self.main_type
+    }
+
+    pub fn get_subtype(&self) -> &str {
+        &self.subtype
+    }
+
+    // just 'type/subtype' without any parameters
+    pub fn essence(&self) -> String {
+        format!("{}/{}", self.main_type, self.subtype)
+    }
+
+    pub fn get_param(&self, name: &str) -> Option<&str> {
+        self.params.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn params(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.params.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    // 'text/html' or 'TEXT/HTML' both match, parameters are ignored
+    pub fn is(&self, essence: &str) -> bool {
+        match essence.split_once('/') {
+            Some((main_type, subtype)) => {
+                self.main_type.eq_ignore_ascii_case(main_type.trim()) && self.subtype.eq_ignore_ascii_case(subtype.trim())
+            },
+            None => false,
+        }
+    }
+}
+
+impl FromStr for Mime {
+    type Err = HTTPError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let essence = s.split(';').next().unwrap_or("").trim();
+        let (main_type, subtype) = match essence.split_once('/') {
+            Some((main_type, subtype)) if is_token(main_type) && is_token(subtype) => (main_type, subtype),
+            _ => return Err(HTTPError::InvalidContentType),
+        };
+
+        let mut mime = Self::new(main_type, subtype);
+        for (name, value) in parse_params(s) {
+            if !is_token(&name) {
+                return Err(HTTPError::InvalidContentType);
+            }
+            mime = mime.with_param(&name, &value);
+        }
+
+        Ok(mime)
+    }
+}
+
+impl std::fmt::Display for Mime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.main_type, self.subtype)?;
+        for (name, value) in &self.params {
+            match is_token(value) {
+                true => write!(f, "; {}={}", name, value)?,
+                false => write!(f, "; {}=\"{}\"", name, value.replace('\\', "\\\\").replace('"', "\\\""))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// the parameters after the first ';' of a header like Content-Type or
+// Content-Disposition, 'form-data; name="a;b"; filename=c.png'. Quotes are
+// respected so a ';' inside of one doesnt split it
+pub fn parse_params(value: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut escaped = false;
+    for c in value.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            ';' if !in_quotes => {
+                params.push(std::mem::take(&mut current));
+                continue;
+            },
+            _ => {},
+        }
+        current.push(c);
+    }
+    params.push(current);
+
+    params.iter()
+        .skip(1)
+        .filter_map(|param| param.split_once('='))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), unquote(value.trim())))
+        .collect()
+}
+
+// strips the quotes off of a parameter value and undoes any \ escapes in it
+fn unquote(value: &str) -> String {
+    match value.strip_prefix('"').and_then(|value| value.strip_suffix('"')) {
+        None => value.to_owned(),
+        Some(inner) => {
+            let mut unquoted = String::with_capacity(inner.len());
+            let mut chars = inner.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => unquoted.extend(chars.next()),
+                    c => unquoted.push(c),
+                }
+            }
+            unquoted
+        },
+    }
+}
+
+// the characters allowed in a type, subtype or parameter name without quotes
+fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+// what a file gets sent as based on its extension, the common web formats
+// are in here from the start and more can be added with register
+#[derive(Debug, Clone)]
+pub struct MimeRegistry {
+    types: HashMap<String, ContentType>,
+    // used for extensions that arent in the table, None means they get a 400
+    fallback: Option<ContentType>,
+}
+
+impl MimeRegistry {
+    pub fn new() -> Self {
+        let defaults = [
+            ("html", ContentType::Html),
+            ("htm", ContentType::Html),
+            ("css", ContentType::Css),
+            ("js", ContentType::JavaScript),
+            ("mjs", ContentType::JavaScript),
+            ("json", ContentType::Json),
+            ("map", ContentType::Json),
+            ("txt", ContentType::PlainText),
+            ("md", ContentType::Markdown),
+            ("wasm", ContentType::Wasm),
+            ("wgsl", ContentType::Wgsl),
+            ("png", ContentType::Image(ImageType::Png)),
+            ("svg", ContentType::Image(ImageType::Svg)),
+            ("ico", ContentType::Image(ImageType::XIcon)),
+            ("jpg", ContentType::Image(ImageType::Jpeg)),
+            ("jpeg", ContentType::Image(ImageType::Jpeg)),
+            ("gif", ContentType::Image(ImageType::Gif)),
+            ("webp", ContentType::Image(ImageType::Webp)),
+            ("avif", ContentType::Image(ImageType::Avif)),
+            ("ttf", ContentType::Font(FontType::Ttf)),
+            ("otf", ContentType::Font(FontType::Otf)),
+            ("ttc", ContentType::Font(FontType::Collection)),
+            ("woff", ContentType::Font(FontType::Woff)),
+            ("woff2", ContentType::Font(FontType::Woff2)),
+            ("xml", ContentType::Other("application/xml")),
+            ("csv", ContentType::Other("text/csv")),
+            ("pdf", ContentType::Other("application/pdf")),
+            ("zip", ContentType::Other("application/zip")),
+            ("mp4", ContentType::Other("video/mp4")),
+            ("webm", ContentType::Other("video/webm")),
+            ("mp3", ContentType::Other("audio/mpeg")),
+            ("ogg", ContentType::Other("audio/ogg")),
+            ("wav", ContentType::Other("audio/wav")),
+            ("glb", ContentType::Other("model/gltf-binary")),
+            ("gltf", ContentType::Other("model/gltf+json")),
+        ];
+
+        Self {
+            types: defaults.into_iter()
+                .map(|(extension, content_type)| (extension.to_owned(), content_type))
+                .collect(),
+            fallback: Some(ContentType::OctetStream),
+        }
+    }
+
+    // adds or replaces the type sent for files ending in extension, 'glb'
+    // and '.glb' are the same thing
+    pub fn register(&mut self, extension: &str, content_type: ContentType) {
+        let extension = extension.trim_start_matches('.').to_ascii_lowercase();
+        self.types.insert(extension, content_type);
+    }
+
+    pub fn set_fallback(&mut self, fallback: Option<ContentType>) {
+        self.fallback = fallback;
+    }
+
+    pub fn get(&self, extension: &str) -> Option<ContentType> {
+        self.types.get(&extension.to_ascii_lowercase()).copied()
+    }
+
+    // the type for a file, the fallback if its extension is unknown
+    pub fn lookup(&self, path: &Path) -> Option<ContentType> {
+        path.extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(|extension| self.get(extension))
+            .or(self.fallback)
+    }
+}
+
+impl Default for MimeRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extensions_are_looked_up_without_caring_about_case() {
+        let registry = MimeRegistry::new();
+        assert!(matches!(registry.lookup(Path::new("/site/index.HTML")), Some(ContentType::Html)));
+        assert!(matches!(registry.lookup(Path::new("app.wasm")), Some(ContentType::Wasm)));
+        assert!(matches!(registry.lookup(Path::new("font.woff2")), Some(ContentType::Font(FontType::Woff2))));
+        assert!(matches!(registry.get("PDF"), Some(ContentType::Other("application/pdf"))));
+    }
+
+    #[test]
+    fn unknown_extensions_get_the_fallback() {
+        let mut registry = MimeRegistry::new();
+        assert!(matches!(registry.lookup(Path::new("data.xyz")), Some(ContentType::OctetStream)));
+        assert!(matches!(registry.lookup(Path::new("Makefile")), Some(ContentType::OctetStream)));
+
+        registry.set_fallback(None);
+        assert!(registry.lookup(Path::new("data.xyz")).is_none());
+        assert!(registry.lookup(Path::new("page.html")).is_some());
+    }
+
+    #[test]
+    fn registered_types_replace_the_defaults() {
+        let mut registry = MimeRegistry::new();
+        registry.register(".CBMD", ContentType::Other("application/x-cbmd"));
+        registry.register("txt", ContentType::Markdown);
+        assert!(matches!(registry.get("cbmd"), Some(ContentType::Other("application/x-cbmd"))));
+        assert!(matches!(registry.get("txt"), Some(ContentType::Markdown)));
+    }
+
+    #[test]
+    fn mimes_keep_their_parameters() {
+        let mime = Mime::from_str("Multipart/Form-Data; Boundary=\"a;b\"; charset=utf-8").unwrap();
+        assert!(mime.is("multipart/form-data"));
+        assert_eq!(mime.essence(), "multipart/form-data");
+        assert_eq!(mime.get_param("boundary"), Some("a;b"));
+        assert_eq!(mime.to_string(), "multipart/form-data; boundary=\"a;b\"; charset=utf-8");
+
+        assert!(Mime::from_str("text").is_err());
+        assert!(Mime::from_str("text/html; bad name=1").is_err());
+    }
+}
@@ -0,0 +1,529 @@
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::headers::Headers;
+use crate::mime;
+use crate::types::{read_error, HTTPError};
+
+// how much gets read at a time while looking for the next boundary
+const READ_SIZE: usize = 64 * 1024;
+// the headers of a single part, way more than a name and a filename need
+const MAX_PART_HEADER_SIZE: usize = 8 * 1024;
+// rfc 2046 says boundaries are 1 to 70 characters
+const MAX_BOUNDARY_LENGTH: usize = 70;
+
+// gives each temp file a different name
+static UPLOAD_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// multipart bodies are parsed as they come in instead of being read into
+// memory first, so these are what caps an upload rather than max_body_size
+#[derive(Debug, Clone)]
+pub struct MultipartLimits {
+    pub max_parts: usize,
+    pub max_part_size: u64,
+    // the whole body as its sent, boundaries and part headers included
+    pub max_total_size: u64,
+    // parts bigger than this get written to a temp file instead of memory
+    pub memory_threshold: usize,
+    // None for the systems temp directory
+    pub temp_dir: Option<PathBuf>,
+}
+
+impl Default for MultipartLimits {
+    fn default() -> Self {
+        Self {
+            max_parts: 32,
+            max_part_size: 10 * 1024 * 1024,
+            max_total_size: 20 * 1024 * 1024,
+            memory_threshold: 256 * 1024,
+            temp_dir: None,
+        }
+    }
+}
+
+// a multipart/form-data body split into its parts, in the order they came in
+#[derive(Debug, Default)]
+pub struct Multipart {
+    parts: Vec<Part>,
+}
+
+impl Multipart {
+    // boundary is the boundary= parameter of the Content-Type header
+    pub fn parse<R: Read>(reader: R, boundary: &str, limits: &MultipartLimits) -> Result<Self, HTTPError> {
+        if boundary.is_empty() || boundary.len() > MAX_BOUNDARY_LENGTH {
+            return Err(HTTPError::InvalidContentType);
+        }
+
+        // every delimiter but the first comes after a new line, starting the
+        // buffer with one means they can all be looked for the same way
+        let mut scanner = Scanner {
+            reader,
+            buffer: b"\r\n".to_vec(),
+            read: 0,
+            max_size: limits.max_total_size,
+        };
+        let delimiter = format!("\r\n--{}", boundary).into_bytes();
+
+        // anything before the first boundary is a preamble and gets ignored
+        scanner.read_until(&delimiter, |_| Ok(()))?;
+
+        let mut parts = Vec::new();
+        loop {
+            // '--' after the boundary means that was the last part
+            if scanner.peek(2)? == b"--" {
+                break;
+            }
+            // there can be whitespace left after the boundary but nothing else
+            let mut padding = Vec::new();
+            scanner.read_until(b"\r\n", |data| {
+                padding.extend_from_slice(data);
+                Ok(())
+            })?;
+            if padding.iter().any(|byte| !matches!(byte, b' ' | b'\t')) {
+                return Err(HTTPError::InvalidContent);
+            }
+
+            if parts.len() >= limits.max_parts {
+                return Err(HTTPError::ContentTooLarge);
+            }
+
+            let headers = read_part_headers(&mut scanner)?;
+            let mut sink = PartSink::new(limits);
+            scanner.read_until(&delimiter, |data| sink.write(data))?;
+            parts.push(Part::new(headers, sink.finish()));
+        }
+
+        Ok(Self {
+            parts,
+        })
+    }
+
+    pub fn parts(&self) -> &[Part] {
+        &self.parts
+    }
+
+    // the first part sent with this name
+    pub fn get(&self, name: &str) -> Option<&Part> {
+        self.parts.iter().find(|part| part.get_name() == Some(name))
+    }
+
+    // for things like '<input type="file" multiple>' which send many parts
+    // with the same name
+    pub fn get_all(&self, name: &str) -> Vec<&Part> {
+        self.parts.iter()
+            .filter(|part| part.get_name() == Some(name))
+            .collect()
+    }
+
+    // the value of a plain form field
+    pub fn text(&self, name: &str) -> Option<&str> {
+        self.get(name)?.text()
+    }
+}
+
+// a parameter of a header like Content-Disposition, 'form-data; name="file"'
+fn header_param(value: &str, name: &str) -> Option<String> {
+    mime::parse_params(value)
+        .into_iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value)
+}
+
+fn read_part_headers<R: Read>(scanner: &mut Scanner<R>) -> Result<Headers, HTTPError> {
+    // a part doesnt have to have any headers at all
+    if scanner.peek(2)? == b"\r\n" {
+        scanner.buffer.drain(..2);
+        return Ok(Headers::new());
+    }
+
+    let mut raw = Vec::new();
+    scanner.read_until(b"\r\n\r\n", |data| {
+        raw.extend_from_slice(data);
+        match raw.len() > MAX_PART_HEADER_SIZE {
+            true => Err(HTTPError::ContentTooLarge),
+            false => Ok(()),
+        }
+    })?;
+
+    match String::from_utf8(raw) {
+        Ok(raw) => Headers::from_str(&raw),
+        Err(_) => Err(HTTPError::InvalidHeader),
+    }
+}
+
+// reads through the body looking for boundaries without ever needing more
+// than a little bit of it in memory at once
+struct Scanner<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    // how much of the body has been read so far, everything counts so even
+    // a never ending preamble gets cut off
+    read: u64,
+    max_size: u64,
+}
+
+impl<R: Read> Scanner<R> {
+    // false once there is nothing left to read
+    fn fill(&mut self) -> Result<bool, HTTPError> {
+        let start = self.buffer.len();
+        self.buffer.resize(start + READ_SIZE, 0);
+        let read = loop {
+            match self.reader.read(&mut self.buffer[start..]) {
+                Ok(read) => break read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.buffer.truncate(start);
+                    // a client that stalls part way through an upload gets a 408
+                    return Err(read_error(e, HTTPError::InvalidContent));
+                },
+            }
+        };
+        self.buffer.truncate(start + read);
+
+        self.read += read as u64;
+        if self.read > self.max_size {
+            return Err(HTTPError::ContentTooLarge);
+        }
+        Ok(read > 0)
+    }
+
+    // the next count bytes without using them up, an error if the body ends first
+    fn peek(&mut self, count: usize) -> Result<&[u8], HTTPError> {
+        while self.buffer.len() < count {
+            if !self.fill()? {
+                return Err(HTTPError::InvalidContent);
+            }
+        }
+        Ok(&self.buffer[..count])
+    }
+
+    // hands everything before needle to sink (in pieces) and then skips past
+    // needle, an error if the body ends before needle is found
+    fn read_until<F>(&mut self, needle: &[u8], mut sink: F) -> Result<(), HTTPError>
+    where
+        F: FnMut(&[u8]) -> Result<(), HTTPError>,
+    {
+        loop {
+            if let Some(position) = find(&self.buffer, needle) {
+                sink(&self.buffer[..position])?;
+                self.buffer.drain(..position + needle.len());
+                return Ok(());
+            }
+
+            // the end of the buffer could be the start of needle so that
+            // much has to stay around until more has been read
+            let keep = needle.len() - 1;
+            if self.buffer.len() > keep {
+                let flush = self.buffer.len() - keep;
+                sink(&self.buffer[..flush])?;
+                self.buffer.drain(..flush);
+            }
+
+            if !self.fill()? {
+                return Err(HTTPError::InvalidContent);
+            }
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+// where the body of a part goes while its being read, memory to start
+// with and a temp file once it gets too big
+struct PartSink<'a> {
+    limits: &'a MultipartLimits,
+    memory: Vec<u8>,
+    file: Option<(File, TempFile)>,
+    length: u64,
+}
+
+impl<'a> PartSink<'a> {
+    fn new(limits: &'a MultipartLimits) -> Self {
+        Self {
+            limits,
+            memory: Vec::new(),
+            file: None,
+            length: 0,
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), HTTPError> {
+        self.length += data.len() as u64;
+        if self.length > self.limits.max_part_size {
+            return Err(HTTPError::ContentTooLarge);
+        }
+
+        if self.file.is_none() && self.memory.len() + data.len() > self.limits.memory_threshold {
+            let temp_dir = self.limits.temp_dir.clone().unwrap_or_else(env::temp_dir);
+            let (mut file, temp) = TempFile::create(&temp_dir).map_err(log_upload_error)?;
+            file.write_all(&self.memory).map_err(log_upload_error)?;
+            self.memory = Vec::new();
+            self.file = Some((file, temp));
+        }
+
+        match &mut self.file {
+            Some((file, _)) => file.write_all(data).map_err(log_upload_error),
+            None => {
+                self.memory.extend_from_slice(data);
+                Ok(())
+            },
+        }
+    }
+
+    fn finish(self) -> PartData {
+        match self.file {
+            Some((_, temp)) => PartData::File {
+                temp,
+                length: self.length,
+            },
+            None => PartData::Memory(self.memory),
+        }
+    }
+}
+
+// the disk filling up isnt the clients fault but theres nothing else to
+// tell them, the real reason gets logged
+fn log_upload_error(error: io::Error) -> HTTPError {
+    println!("Error: {}, while saving an upload", error);
+    HTTPError::InvalidContent
+}
+
+// a file that gets deleted when its dropped, save_to copies it somewhere
+// that lasts
+#[derive(Debug)]
+struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    fn create(dir: &Path) -> Result<(File, Self), io::Error> {
+        loop {
+            let count = UPLOAD_COUNTER.fetch_add(1, Ordering::Relaxed);
+            let path = dir.join(format!("bottomless-upload-{}-{}", std::process::id(), count));
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => return Ok((file, Self { path })),
+                // left over from a server that didnt clean up after itself
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[derive(Debug)]
+enum PartData {
+    Memory(Vec<u8>),
+    File {
+        temp: TempFile,
+        length: u64,
+    },
+}
+
+// one field of the form, either a plain value or an uploaded file
+#[derive(Debug)]
+pub struct Part {
+    headers: Headers,
+    name: Option<String>,
+    filename: Option<String>,
+    data: PartData,
+}
+
+impl Part {
+    fn new(headers: Headers, data: PartData) -> Self {
+        let disposition = headers.get("Content-Disposition").unwrap_or("");
+        let name = header_param(disposition, "name");
+        // only the last part of the name, browsers are supposed to do this but
+        // a path in here should never be trusted
+        let filename = header_param(disposition, "filename")
+            .and_then(|filename| {
+                filename.rsplit(['/', '\\'])
+                    .next()
+                    .filter(|name| !name.is_empty() && *name != "." && *name != "..")
+                    .map(str::to_owned)
+            });
+
+        Self {
+            headers,
+            name,
+            filename,
+            data,
+        }
+    }
+
+    pub fn get_name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    // whatever the file was called on the clients computer, without any path
+    pub fn get_filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    // text/plain when the part doesnt say
+    pub fn get_content_type(&self) -> &str {
+        self.headers.get("Content-Type").unwrap_or("text/plain")
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    pub fn len(&self) -> u64 {
+        match &self.data {
+            PartData::Memory(data) => data.len() as u64,
+            PartData::File { length, .. } => *length,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // None when the part was big enough to end up on disk
+    pub fn bytes(&self) -> Option<&[u8]> {
+        match &self.data {
+            PartData::Memory(data) => Some(data),
+            PartData::File { .. } => None,
+        }
+    }
+
+    pub fn text(&self) -> Option<&str> {
+        std::str::from_utf8(self.bytes()?).ok()
+    }
+
+    // reads the part no matter where it ended up
+    pub fn open(&self) -> Result<Box<dyn Read + '_>, io::Error> {
+        match &self.data {
+            PartData::Memory(data) => Ok(Box::new(Cursor::new(data.as_slice()))),
+            PartData::File { temp, .. } => Ok(Box::new(File::open(&temp.path)?)),
+        }
+    }
+
+    // keeps the part around after the request is done
+    pub fn save_to(&self, path: &Path) -> Result<(), io::Error> {
+        match &self.data {
+            PartData::Memory(data) => fs::write(path, data),
+            // the temp file still gets deleted when the part is dropped
+            PartData::File { temp, .. } => fs::copy(&temp.path, path).map(|_| ()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"preamble\r\n--XyZ\r\nContent-Disposition: form-data; name=\"email\"\r\n\r\na@b.c\r\n--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"../../notes.txt\"\r\nContent-Type: text/plain\r\n\r\nline one\r\nline two\r\n--XyZ--\r\n";
+
+    #[test]
+    fn parts_are_split_on_the_boundary() {
+        let form = Multipart::parse(BODY, "XyZ", &MultipartLimits::default()).unwrap();
+        assert_eq!(form.parts().len(), 2);
+        assert_eq!(form.text("email"), Some("a@b.c"));
+
+        let file = form.get("file").unwrap();
+        assert_eq!(file.get_filename(), Some("notes.txt"));
+        assert_eq!(file.get_content_type(), "text/plain");
+        assert_eq!(file.bytes(), Some(&b"line one\r\nline two"[..]));
+    }
+
+    #[test]
+    fn limits_are_enforced() {
+        let limits = MultipartLimits {
+            max_part_size: 10,
+            ..MultipartLimits::default()
+        };
+        assert!(matches!(Multipart::parse(BODY, "XyZ", &limits), Err(HTTPError::ContentTooLarge)));
+
+        let limits = MultipartLimits {
+            max_parts: 1,
+            ..MultipartLimits::default()
+        };
+        assert!(matches!(Multipart::parse(BODY, "XyZ", &limits), Err(HTTPError::ContentTooLarge)));
+    }
+
+    #[test]
+    fn the_whole_body_counts_towards_the_total() {
+        let limits = MultipartLimits {
+            max_total_size: BODY.len() as u64 - 1,
+            ..MultipartLimits::default()
+        };
+        assert!(matches!(Multipart::parse(BODY, "XyZ", &limits), Err(HTTPError::ContentTooLarge)));
+
+        // a preamble that never ends still gets cut off
+        let endless = io::repeat(b'a').take(1024 * 1024);
+        let limits = MultipartLimits {
+            max_total_size: 64 * 1024,
+            ..MultipartLimits::default()
+        };
+        assert!(matches!(Multipart::parse(endless, "XyZ", &limits), Err(HTTPError::ContentTooLarge)));
+    }
+
+    // boundaries split across reads still have to be found
+    #[test]
+    fn bodies_can_come_in_a_byte_at_a_time() {
+        struct OneByte<'a>(&'a [u8]);
+        impl Read for OneByte<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let count = buf.len().min(1).min(self.0.len());
+                buf[..count].copy_from_slice(&self.0[..count]);
+                self.0 = &self.0[count..];
+                Ok(count)
+            }
+        }
+
+        let mut body = OneByte(BODY);
+        let form = Multipart::parse(&mut body, "XyZ", &MultipartLimits::default()).unwrap();
+        assert_eq!(form.text("email"), Some("a@b.c"));
+        assert_eq!(form.get("file").unwrap().bytes(), Some(&b"line one\r\nline two"[..]));
+    }
+
+    #[test]
+    fn big_parts_go_to_a_temp_file() {
+        let temp_dir = env::temp_dir().join(format!("bottomless-multipart-test-{}", std::process::id()));
+        fs::create_dir_all(&temp_dir).unwrap();
+        let limits = MultipartLimits {
+            memory_threshold: 8,
+            temp_dir: Some(temp_dir.clone()),
+            ..MultipartLimits::default()
+        };
+
+        let form = Multipart::parse(BODY, "XyZ", &limits).unwrap();
+        // small enough to stay in memory
+        assert_eq!(form.text("email"), Some("a@b.c"));
+
+        let file = form.get("file").unwrap();
+        assert_eq!(file.bytes(), None);
+        assert_eq!(file.len(), 18);
+        let mut contents = Vec::new();
+        file.open().unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"line one\r\nline two");
+
+        let saved = temp_dir.join("saved.txt");
+        file.save_to(&saved).unwrap();
+        assert_eq!(fs::read(&saved).unwrap(), b"line one\r\nline two");
+        fs::remove_file(&saved).unwrap();
+
+        assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 1);
+        drop(form);
+        assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+        fs::remove_dir(&temp_dir).unwrap();
+    }
+
+    #[test]
+    fn a_missing_end_is_invalid() {
+        let body = &BODY[..BODY.len() - 9];
+        assert!(matches!(Multipart::parse(body, "XyZ", &MultipartLimits::default()), Err(HTTPError::InvalidContent)));
+    }
+}
@@ -0,0 +1,372 @@
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use crate::apis::ApiRegister;
+use crate::mime::MimeRegistry;
+use crate::types::CorsPolicy;
+
+// maps the path a request asks for onto where it actually lives, either a
+// directory on disk or the api register. Mounts are matched by the longest
+// prefix so '/examples' wins over '/' for '/examples/line'
+#[derive(Debug, Default)]
+pub struct Router {
+    mounts: Vec<Mount>,
+    mime_types: MimeRegistry,
+    cors: CorsPolicy,
+}
+
+#[derive(Debug)]
+struct Mount {
+    prefix: String,
+    target: MountTarget,
+}
+
+#[derive(Debug)]
+enum MountTarget {
+    Directory(StaticDir),
+    Api(Arc<ApiRegister>),
+}
+
+// a directory of files served as is, the options are all off by default
+#[derive(Debug, Clone)]
+pub struct StaticDir {
+    root: PathBuf,
+    index: Option<String>,
+    clean_urls: bool,
+    listing: bool,
+    not_found: Option<PathBuf>,
+}
+
+impl StaticDir {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            index: None,
+            clean_urls: false,
+            listing: false,
+            not_found: None,
+        }
+    }
+
+    // the file sent when a directory is asked for, usually index.html
+    pub fn index(mut self, index: &str) -> Self {
+        self.index = Some(index.to_owned());
+        self
+    }
+
+    // lets '/blog' be sent '/blog.html'
+    pub fn clean_urls(mut self, clean_urls: bool) -> Self {
+        self.clean_urls = clean_urls;
+        self
+    }
+
+    // directories without an index get a page linking to everything in them
+    pub fn listing(mut self, listing: bool) -> Self {
+        self.listing = listing;
+        self
+    }
+
+    // page sent along with a 404 when nothing was found
+    pub fn not_found(mut self, page: impl Into<PathBuf>) -> Self {
+        self.not_found = Some(page.into());
+        self
+    }
+
+    // works out which file (or directory) the path inside of the mount is
+    fn resolve(&self, request_path: &str, rest: &str) -> Route<'_> {
+        // only plain names make it in, the request line already turned
+        // away anything trying to ../ out of here but this keeps a stray
+        // root or prefix component from replacing the whole path
+        let relative = Path::new(rest)
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(name),
+                _ => None,
+            })
+            .collect::<PathBuf>();
+        let path = self.root.join(&relative);
+
+        // dot files (.DS_Store, .git) are never meant to be served, now that
+        // unknown extensions get sent instead of refused they have to be hidden
+        let hidden = relative.iter().any(|name| name.to_string_lossy().starts_with('.'));
+        if hidden {
+            return Route::NotFound(self.not_found.clone());
+        }
+
+        if path.is_file() {
+            return self.checked_file(&path);
+        }
+
+        if self.clean_urls && path.extension().is_none() && !rest.ends_with('/') {
+            let html = path.with_extension("html");
+            if relative.file_name().is_some() && html.is_file() {
+                return self.checked_file(&html);
+            }
+        }
+
+        if path.is_dir() {
+            // relative links in the index or listing would point at the parent
+            // directory without the trailing slash
+            if !request_path.ends_with('/') {
+                let location = request_path.split('/')
+                    .map(encode_path_segment)
+                    .collect::<Vec<String>>()
+                    .join("/");
+                return Route::Redirect(format!("{}/", location));
+            }
+
+            if let Some(index) = &self.index {
+                let index = path.join(index);
+                if index.is_file() {
+                    return self.checked_file(&index);
+                }
+            }
+
+            if self.listing {
+                return match self.inside_root(&path) {
+                    Some(path) => Route::Listing(path),
+                    None => Route::NotFound(self.not_found.clone()),
+                };
+            }
+        }
+
+        Route::NotFound(self.not_found.clone())
+    }
+
+    fn checked_file(&self, path: &Path) -> Route<'_> {
+        match self.inside_root(path) {
+            Some(path) => Route::File(path),
+            None => Route::NotFound(self.not_found.clone()),
+        }
+    }
+
+    // the request path was already cleaned up but a symlink inside of the
+    // directory could still point anywhere, so this resolves where the file
+    // really is and makes sure thats still under the root
+    fn inside_root(&self, path: &Path) -> Option<PathBuf> {
+        let root = self.root.canonicalize().ok()?;
+        let path = path.canonicalize().ok()?;
+        if !path.starts_with(&root) {
+            println!("Refusing to serve {:?} as its outside of {:?}", path, root);
+            return None;
+        }
+        Some(path)
+    }
+}
+
+// what the router decided to do with a request
+#[derive(Debug)]
+pub enum Route<'a> {
+    File(PathBuf),
+    Listing(PathBuf),
+    // to the same path with a slash on the end
+    Redirect(String),
+    // with the page to send if the mount has one
+    NotFound(Option<PathBuf>),
+    Api(&'a ApiRegister),
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self {
+            mounts: Vec::new(),
+            mime_types: MimeRegistry::new(),
+            cors: CorsPolicy::default(),
+        }
+    }
+
+    // what files from the directory mounts are sent as
+    pub fn set_mime_types(&mut self, mime_types: MimeRegistry) {
+        self.mime_types = mime_types;
+    }
+
+    pub fn mime_types(&self) -> &MimeRegistry {
+        &self.mime_types
+    }
+
+    // which other sites can call the apis and read the files
+    pub fn set_cors(&mut self, cors: CorsPolicy) {
+        self.cors = cors;
+    }
+
+    pub fn cors(&self) -> &CorsPolicy {
+        &self.cors
+    }
+
+    pub fn mount_dir(&mut self, prefix: &str, dir: StaticDir) {
+        self.add_mount(prefix, MountTarget::Directory(dir));
+    }
+
+    pub fn mount_api(&mut self, prefix: &str, apis: Arc<ApiRegister>) {
+        self.add_mount(prefix, MountTarget::Api(apis));
+    }
+
+    fn add_mount(&mut self, prefix: &str, target: MountTarget) {
+        let prefix = prefix.trim_end_matches('/').to_owned();
+        self.mounts.retain(|mount| mount.prefix != prefix);
+        self.mounts.push(Mount {
+            prefix,
+            target,
+        });
+        // longest first so the first match is always the most specific one
+        self.mounts.sort_by_key(|mount| std::cmp::Reverse(mount.prefix.len()));
+    }
+
+    pub fn route(&self, path: &str) -> Route<'_> {
+        for mount in &self.mounts {
+            let rest = match path.strip_prefix(mount.prefix.as_str()) {
+                // '/examples' shouldnt match '/examplesfoo'
+                Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+                _ => continue,
+            };
+
+            return match &mount.target {
+                MountTarget::Api(apis) => Route::Api(apis),
+                MountTarget::Directory(dir) => dir.resolve(path, rest),
+            };
+        }
+
+        Route::NotFound(None)
+    }
+}
+
+// a bare bones html page linking to everything in the directory
+pub fn render_listing(request_path: &str, dir: &Path) -> Result<String, io::Error> {
+    let mut entries = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let is_dir = entry.file_type().ok()?.is_dir();
+            Some((name, is_dir))
+        })
+        // dot files are usually things that werent meant to be seen
+        .filter(|(name, _)| !name.starts_with('.'))
+        .collect::<Vec<(String, bool)>>();
+    // directories first then alphabetical
+    entries.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    let title = escape_html(request_path);
+    let mut page = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {0}</title></head>\n<body>\n<h1>Index of {0}</h1>\n<ul>\n",
+        title
+    );
+    if request_path != "/" {
+        page.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for (name, is_dir) in entries {
+        let slash = if is_dir { "/" } else { "" };
+        page.push_str(&format!(
+            "<li><a href=\"{}{}\">{}{}</a></li>\n",
+            encode_path_segment(&name), slash, escape_html(&name), slash
+        ));
+    }
+    page.push_str("</ul>\n</body>\n</html>\n");
+
+    Ok(page)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// percent encodes everything that cant go in a url as is
+fn encode_path_segment(segment: &str) -> String {
+    segment.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    // a site root with a secret file next to it (outside of the root) and
+    // symlinks inside that point out at it, removed again when dropped
+    struct Site {
+        base: PathBuf,
+    }
+
+    impl Site {
+        fn new(name: &str) -> Self {
+            let base = std::env::temp_dir().join(format!("router-test-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&base);
+            let root = base.join("root");
+            fs::create_dir_all(root.join("docs")).unwrap();
+            fs::write(root.join("index.html"), "index").unwrap();
+            fs::write(root.join("docs").join("page.html"), "page").unwrap();
+            fs::write(root.join(".secret"), "hidden").unwrap();
+            fs::write(base.join("secret.txt"), "secret").unwrap();
+            symlink(base.join("secret.txt"), root.join("link.txt")).unwrap();
+            symlink(&base, root.join("escape")).unwrap();
+            symlink(root.join("docs").join("page.html"), root.join("alias.html")).unwrap();
+            Self { base }
+        }
+
+        fn root(&self) -> PathBuf {
+            self.base.join("root")
+        }
+
+        fn router(&self) -> Router {
+            let mut router = Router::new();
+            router.mount_dir("/", StaticDir::new(self.root())
+                .index("index.html")
+                .clean_urls(true));
+            router
+        }
+    }
+
+    impl Drop for Site {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.base);
+        }
+    }
+
+    fn is_file(route: Route<'_>, name: &str) -> bool {
+        matches!(route, Route::File(path) if path.ends_with(name))
+    }
+
+    #[test]
+    fn files_inside_the_root_are_served() {
+        let site = Site::new("inside");
+        let router = site.router();
+        assert!(is_file(router.route("/"), "index.html"));
+        assert!(is_file(router.route("/docs/page.html"), "page.html"));
+        assert!(is_file(router.route("/docs/page"), "page.html"));
+        // a symlink is fine as long as it stays inside
+        assert!(is_file(router.route("/alias.html"), "page.html"));
+    }
+
+    #[test]
+    fn symlinks_out_of_the_root_are_not_found() {
+        let site = Site::new("symlink");
+        let router = site.router();
+        assert!(matches!(router.route("/link.txt"), Route::NotFound(_)));
+        assert!(matches!(router.route("/escape/secret.txt"), Route::NotFound(_)));
+    }
+
+    #[test]
+    fn dot_files_are_not_found() {
+        let site = Site::new("dotfiles");
+        let router = site.router();
+        assert!(matches!(router.route("/.secret"), Route::NotFound(_)));
+    }
+
+    #[test]
+    fn unclean_paths_cant_leave_the_mount() {
+        let site = Site::new("unclean");
+        let mut router = Router::new();
+        router.mount_dir("/docs", StaticDir::new(site.root().join("docs")));
+        // the request line normally cleans these up first, the router still
+        // shouldnt let a stray one through
+        assert!(matches!(router.route("/docs/../index.html"), Route::NotFound(_)));
+        assert!(matches!(router.route("/docs//../../secret.txt"), Route::NotFound(_)));
+    }
+}
@@ -0,0 +1,115 @@
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+// set once SIGTERM or SIGINT comes in, everything that loops forever checks
+// this to know when to stop
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+// when the flag was first seen, the signal handler cant do much so this gets
+// filled in by whoever checks it first
+static STARTED: OnceLock<Instant> = OnceLock::new();
+
+pub fn is_shutting_down() -> bool {
+    let shutting_down = SHUTTING_DOWN.load(Ordering::SeqCst);
+    if shutting_down {
+        STARTED.get_or_init(Instant::now);
+    }
+    shutting_down
+}
+
+// everything that gets some time to finish up on shutdown counts from here
+// so the time limits dont stack up one after another
+pub fn started() -> Option<Instant> {
+    STARTED.get().copied()
+}
+
+// does the same thing as getting a signal, for shutting down from the code
+pub fn request_shutdown() {
+    STARTED.get_or_init(Instant::now);
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+}
+
+// SIGTERM is what systemd and docker send on a deploy, SIGINT is ctrl-c.
+// A second one while still shutting down exits straight away for when
+// something is stuck
+#[cfg(target_os = "linux")]
+pub fn install_signal_handlers() -> Result<(), io::Error> {
+    for signal in [libc::SIGTERM, libc::SIGINT] {
+        let result = unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            // no SA_RESTART so a poll waiting for connections wakes up with EINTR
+            action.sa_flags = 0;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, std::ptr::null_mut())
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+// only things that are safe inside of a signal handler can happen in here,
+// which is an atomic and _exit and not much else
+#[cfg(target_os = "linux")]
+extern "C" fn handle_signal(_signal: libc::c_int) {
+    if SHUTTING_DOWN.swap(true, Ordering::SeqCst) {
+        unsafe { libc::_exit(1) };
+    }
+}
+
+// without libc theres nothing to catch signals with so they just kill the
+// server like they always did
+#[cfg(not(target_os = "linux"))]
+pub fn install_signal_handlers() -> Result<(), io::Error> {
+    Ok(())
+}
+
+// how long a wait for a connection lasts before checking the flag again,
+// a signal wakes it up early anyway this is just in case one gets missed
+#[cfg(target_os = "linux")]
+const ACCEPT_POLL_MS: libc::c_int = 1000;
+
+// the next connection to the listener, None once its time to shut down.
+// accept itself retries when a signal interrupts it so this waits with poll
+// first which doesnt
+#[cfg(target_os = "linux")]
+pub fn next_connection(listener: &TcpListener) -> Option<Result<TcpStream, io::Error>> {
+    use std::os::unix::io::AsRawFd;
+
+    loop {
+        if is_shutting_down() {
+            return None;
+        }
+
+        let mut poll_fd = libc::pollfd {
+            fd: listener.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ready = unsafe { libc::poll(&mut poll_fd, 1, ACCEPT_POLL_MS) };
+        match ready {
+            0 => continue,
+            ready if ready > 0 => return Some(listener.accept().map(|(stream, _)| stream)),
+            _ => {
+                let error = io::Error::last_os_error();
+                match error.kind() {
+                    io::ErrorKind::Interrupted => continue,
+                    _ => return Some(Err(error)),
+                }
+            },
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn next_connection(listener: &TcpListener) -> Option<Result<TcpStream, io::Error>> {
+    match is_shutting_down() {
+        true => None,
+        false => Some(listener.accept().map(|(stream, _)| stream)),
+    }
+}
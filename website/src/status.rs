@@ -0,0 +1,180 @@
+// every status code in the IANA registry with its canonical reason phrase.
+// the macro just saves writing out the same list three times for the enum,
+// the number and the phrase
+macro_rules! status_codes {
+    ($($name:ident = $code:literal, $reason:literal;)+) => {
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub enum StatusCode {
+            $($name,)+
+            // anything not in the registry, still sent as is with a generic
+            // reason phrase based on its class. Only made by From<u16> so it
+            // cant end up outside of 100-599
+            Other(UnregisteredCode),
+        }
+
+        impl StatusCode {
+            pub fn as_u16(&self) -> u16 {
+                match self {
+                    $(Self::$name => $code,)+
+                    Self::Other(code) => code.0,
+                }
+            }
+
+            pub fn reason_phrase(&self) -> &'static str {
+                match self {
+                    $(Self::$name => $reason,)+
+                    Self::Other(code) => match code.0 {
+                        100..=199 => "Informational",
+                        200..=299 => "Success",
+                        300..=399 => "Redirection",
+                        400..=499 => "Client Error",
+                        _ => "Server Error",
+                    },
+                }
+            }
+        }
+
+        impl From<u16> for StatusCode {
+            fn from(value: u16) -> Self {
+                match value {
+                    $($code => Self::$name,)+
+                    // a status code has to be three digits, anything else would
+                    // make a broken status line so it gets turned into a 500
+                    100..=599 => Self::Other(UnregisteredCode(value)),
+                    _ => {
+                        println!("Invalid status code: {}, sending 500 instead", value);
+                        Self::InternalServerError
+                    },
+                }
+            }
+        }
+    };
+}
+
+// a three digit code thats not in the registry, the field is private so the
+// only way to get one is through StatusCode::from which checks the range
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct UnregisteredCode(u16);
+
+impl UnregisteredCode {
+    pub fn get(&self) -> u16 {
+        self.0
+    }
+}
+
+status_codes! {
+    Continue = 100, "Continue";
+    SwitchingProtocols = 101, "Switching Protocols";
+    Processing = 102, "Processing";
+    EarlyHints = 103, "Early Hints";
+
+    Ok = 200, "OK";
+    Created = 201, "Created";
+    Accepted = 202, "Accepted";
+    NonAuthoritativeInformation = 203, "Non-Authoritative Information";
+    NoContent = 204, "No Content";
+    ResetContent = 205, "Reset Content";
+    PartialContent = 206, "Partial Content";
+    MultiStatus = 207, "Multi-Status";
+    AlreadyReported = 208, "Already Reported";
+    ImUsed = 226, "IM Used";
+
+    MultipleChoices = 300, "Multiple Choices";
+    MovedPermanently = 301, "Moved Permanently";
+    Found = 302, "Found";
+    SeeOther = 303, "See Other";
+    NotModified = 304, "Not Modified";
+    UseProxy = 305, "Use Proxy";
+    TemporaryRedirect = 307, "Temporary Redirect";
+    PermanentRedirect = 308, "Permanent Redirect";
+
+    BadRequest = 400, "Bad Request";
+    Unauthorized = 401, "Unauthorized";
+    PaymentRequired = 402, "Payment Required";
+    Forbidden = 403, "Forbidden";
+    NotFound = 404, "Not Found";
+    MethodNotAllowed = 405, "Method Not Allowed";
+    NotAcceptable = 406, "Not Acceptable";
+    ProxyAuthenticationRequired = 407, "Proxy Authentication Required";
+    RequestTimeout = 408, "Request Timeout";
+    Conflict = 409, "Conflict";
+    Gone = 410, "Gone";
+    LengthRequired = 411, "Length Required";
+    PreconditionFailed = 412, "Precondition Failed";
+    ContentTooLarge = 413, "Content Too Large";
+    UriTooLong = 414, "URI Too Long";
+    UnsupportedMediaType = 415, "Unsupported Media Type";
+    RangeNotSatisfiable = 416, "Range Not Satisfiable";
+    ExpectationFailed = 417, "Expectation Failed";
+    ImATeapot = 418, "I'm a teapot";
+    MisdirectedRequest = 421, "Misdirected Request";
+    UnprocessableContent = 422, "Unprocessable Content";
+    Locked = 423, "Locked";
+    FailedDependency = 424, "Failed Dependency";
+    TooEarly = 425, "Too Early";
+    UpgradeRequired = 426, "Upgrade Required";
+    PreconditionRequired = 428, "Precondition Required";
+    TooManyRequests = 429, "Too Many Requests";
+    RequestHeaderFieldsTooLarge = 431, "Request Header Fields Too Large";
+    UnavailableForLegalReasons = 451, "Unavailable For Legal Reasons";
+
+    InternalServerError = 500, "Internal Server Error";
+    NotImplemented = 501, "Not Implemented";
+    BadGateway = 502, "Bad Gateway";
+    ServiceUnavailable = 503, "Service Unavailable";
+    GatewayTimeout = 504, "Gateway Timeout";
+    HttpVersionNotSupported = 505, "HTTP Version Not Supported";
+    VariantAlsoNegotiates = 506, "Variant Also Negotiates";
+    InsufficientStorage = 507, "Insufficient Storage";
+    LoopDetected = 508, "Loop Detected";
+    NotExtended = 510, "Not Extended";
+    NetworkAuthenticationRequired = 511, "Network Authentication Required";
+}
+
+impl StatusCode {
+    // 1xx, 204 and 304 responses never have a body so they dont get
+    // a Content-Length or Content-Type either
+    pub fn allows_body(&self) -> bool {
+        let code = self.as_u16();
+        !((100..200).contains(&code) || code == 204 || code == 304)
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.as_u16())
+    }
+}
+
+// the end of the status line, "404 Not Found"
+impl std::fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.as_u16(), self.reason_phrase())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registered_codes_get_their_own_phrase() {
+        assert_eq!(StatusCode::from(404), StatusCode::NotFound);
+        assert_eq!(StatusCode::NotFound.to_string(), "404 Not Found");
+        assert_eq!(StatusCode::ImATeapot.as_u16(), 418);
+    }
+
+    #[test]
+    fn unregistered_codes_keep_their_number() {
+        let code = StatusCode::from(299);
+        assert!(matches!(code, StatusCode::Other(other) if other.get() == 299));
+        assert_eq!(code.to_string(), "299 Success");
+        assert_eq!(StatusCode::from(599).to_string(), "599 Server Error");
+        assert!(StatusCode::from(299).is_success());
+    }
+
+    #[test]
+    fn codes_that_arent_three_digits_become_500s() {
+        assert_eq!(StatusCode::from(99), StatusCode::InternalServerError);
+        assert_eq!(StatusCode::from(600), StatusCode::InternalServerError);
+        assert_eq!(StatusCode::from(9999), StatusCode::InternalServerError);
+    }
+}
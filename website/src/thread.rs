@@ -1,63 +1,817 @@
-use std::sync::mpsc::{self, Sender, Receiver};
-use std::sync::{Arc, Mutex};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::collections::VecDeque;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
 use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
+// how many jobs can be waiting for a worker per worker when the queue size
+// isnt given
+const DEFAULT_QUEUE_PER_WORKER: usize = 8;
+
+// how many workers there can be and how long they wait around for
+// something to do, the pool starts with min_workers and adds more (up to
+// max_workers) when jobs start waiting, then slowly drops back down as
+// workers go idle
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    pub min_workers: usize,
+    pub max_workers: usize,
+    // how many jobs can be waiting for a worker at once, past that execute
+    // blocks and try_reserve gives back None
+    pub max_queue: usize,
+    // a worker over min_workers that goes this long without a job stops
+    pub idle_timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            min_workers: 2,
+            max_workers: 16,
+            max_queue: 64,
+            idle_timeout: Duration::from_secs(60),
+        }
+    }
+}
 
-#[allow(dead_code)]
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: Sender<Job>,
+    // behind a mutex so workers can be added and cleaned up from execute
+    workers: Mutex<Vec<Worker>>,
+    shared: Arc<Shared>,
+    max_workers: usize,
+    max_queue: usize,
+    // gives every worker a different name, even ones started after others retired
+    next_id: AtomicUsize,
+}
+
+// everything the workers need from the pool
+struct Shared {
+    queue: Mutex<JobQueue>,
+    // workers wait on job_added and execute waits on room_made when the
+    // queue is full. Waiting lets go of the lock so every idle worker's
+    // timeout runs at the same time instead of one after another
+    job_added: Condvar,
+    room_made: Condvar,
+    max_queue: usize,
+    stats: Arc<PoolStats>,
+    min_workers: usize,
+    idle_timeout: Duration,
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+// when the job was queued so the worker can tell how long it waited
+type QueuedJob = (Instant, Job);
+
+struct JobQueue {
+    jobs: VecDeque<QueuedJob>,
+    // set once the pool is shutting down, workers stop when its empty
+    closed: bool,
+}
+
 impl ThreadPool {
+    // always exactly size workers
     pub fn new(size: usize) -> Self {
-        assert!(size > 0);
+        Self::with_queue(size, size * DEFAULT_QUEUE_PER_WORKER)
+    }
 
-        let (sender, receiver) = mpsc::channel();
+    pub fn with_queue(size: usize, max_queue: usize) -> Self {
+        Self::with_config(PoolConfig {
+            min_workers: size,
+            max_workers: size,
+            max_queue,
+            ..PoolConfig::default()
+        })
+    }
 
-        let receiver = Arc::new(Mutex::new(receiver));
+    pub fn with_config(config: PoolConfig) -> Self {
+        assert!(config.max_workers > 0);
+        assert!(config.min_workers <= config.max_workers);
+        assert!(config.max_queue > 0);
 
-        let mut workers = Vec::with_capacity(size);
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(JobQueue {
+                jobs: VecDeque::with_capacity(config.max_queue),
+                closed: false,
+            }),
+            job_added: Condvar::new(),
+            room_made: Condvar::new(),
+            max_queue: config.max_queue,
+            stats: Arc::new(PoolStats::default()),
+            min_workers: config.min_workers,
+            idle_timeout: config.idle_timeout,
+        });
 
-        (0..size).for_each(|id| workers.push(Worker::new(id, Arc::clone(&receiver))));
+        let pool = ThreadPool {
+            workers: Mutex::new(Vec::with_capacity(config.max_workers)),
+            shared,
+            max_workers: config.max_workers,
+            max_queue: config.max_queue,
+            next_id: AtomicUsize::new(0),
+        };
 
-        ThreadPool {
-            workers,
-            sender,
+        {
+            let mut workers = pool.lock_workers();
+            (0..config.min_workers).for_each(|_| pool.spawn_worker(&mut workers));
         }
+
+        pool
     }
 
+    // waits for room in the queue if its full
     pub fn execute<F>(&self, function: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(function);
+        // counted before the send so the depth is never less than whats
+        // really in the queue, while blocked it can read one over max
+        self.shared.stats.add_queued();
+        self.send(Box::new(function), true);
+    }
+
+    // a spot in the queue if theres one free, for when a full queue should
+    // be answered straight away instead of waited on
+    pub fn try_reserve(&self) -> Option<QueueSlot<'_>> {
+        let stats = &self.shared.stats;
+        let reserved = stats.queued.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| {
+            match queued < self.max_queue {
+                true => Some(queued + 1),
+                false => None,
+            }
+        });
+
+        match reserved {
+            Ok(queued) => {
+                stats.peak_queued.fetch_max(queued + 1, Ordering::Relaxed);
+                Some(QueueSlot {
+                    pool: self,
+                })
+            },
+            Err(_) => {
+                stats.rejected.fetch_add(1, Ordering::Relaxed);
+                None
+            },
+        }
+    }
+
+    // whether execute would have to wait for room right now, unlike
+    // try_reserve this doesnt count as turning anything away
+    pub fn is_full(&self) -> bool {
+        self.shared.stats.get_queued() >= self.max_queue
+    }
+
+    fn send(&self, job: Job, wait_for_room: bool) {
+        self.manage_workers();
+        self.shared.push((Instant::now(), job), wait_for_room);
+    }
+
+    pub fn get_max_queue(&self) -> usize {
+        self.max_queue
+    }
+
+    // shared with the workers so it can be read from anywhere, even after
+    // the pool is gone
+    pub fn stats(&self) -> Arc<PoolStats> {
+        Arc::clone(&self.shared.stats)
+    }
+
+    // lets every job that was already handed out finish, waiting at most
+    // timeout for them. Gives back how many workers were still busy when it
+    // gave up, those get left to finish on their own
+    pub fn shutdown(mut self, timeout: Duration) -> usize {
+        // once the queue is closed the workers run out whats left in it
+        // and then stop
+        self.shared.close();
+        let workers = self.workers.get_mut().unwrap_or_else(PoisonError::into_inner);
+
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline && workers.iter().any(|worker| !worker.thread.is_finished()) {
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        let (finished, unfinished): (Vec<Worker>, Vec<Worker>) = workers
+            .drain(..)
+            .partition(|worker| worker.thread.is_finished());
+        finished.into_iter().for_each(Worker::join);
+
+        for worker in &unfinished {
+            println!("worker-{} didnt finish in time", worker.id);
+        }
+        unfinished.len()
+    }
+
+    // cleans up workers that retired or died, replaces any that died so
+    // there are always min_workers, and adds one if jobs are waiting with
+    // nobody free to take them
+    fn manage_workers(&self) {
+        let mut workers = self.lock_workers();
+
+        let (finished, running): (Vec<Worker>, Vec<Worker>) = workers
+            .drain(..)
+            .partition(|worker| worker.thread.is_finished());
+        *workers = running;
+        // jobs that panic get caught so this is only for something else
+        // going wrong in the worker
+        finished.into_iter().for_each(Worker::join);
+
+        let stats = &self.shared.stats;
+        let missing = self.shared.min_workers.saturating_sub(stats.get_workers());
+        (0..missing).for_each(|_| self.spawn_worker(&mut workers));
+
+        // the job about to be sent is already counted in queued
+        let backed_up = stats.get_queued() > stats.get_idle_workers();
+        if backed_up && stats.get_workers() < self.max_workers {
+            self.spawn_worker(&mut workers);
+            println!("Queue backing up, now running {} workers", stats.get_workers());
+        }
+    }
+
+    fn spawn_worker(&self, workers: &mut Vec<Worker>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        match Worker::new(id, Arc::clone(&self.shared)) {
+            Ok(worker) => workers.push(worker),
+            Err(e) => println!("Error: {}, couldnt start worker-{}", e, id),
+        }
+    }
+
+    fn lock_workers(&self) -> MutexGuard<'_, Vec<Worker>> {
+        self.workers.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Drop for ThreadPool {
+    // waits for however long the workers take, use shutdown for a time limit
+    fn drop(&mut self) {
+        self.shared.close();
+        let workers = self.workers.get_mut().unwrap_or_else(PoisonError::into_inner);
+        workers.drain(..).for_each(Worker::join);
+    }
+}
+
+// a place in the queue saved by try_reserve, dropping it without calling
+// execute gives the place back
+pub struct QueueSlot<'a> {
+    pool: &'a ThreadPool,
+}
+
+impl QueueSlot<'_> {
+    // never blocks, the room was already made when the slot was reserved
+    pub fn execute<F>(self, function: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let pool = self.pool;
+        // the worker takes the spot back once it picks the job up
+        std::mem::forget(self);
+        pool.send(Box::new(function), false);
+    }
+}
 
-        self.sender.send(job).unwrap();
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.pool.shared.stats.queued.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// counters for how busy and backed up the pool is, updated by the pool and
+// the workers
+#[derive(Debug, Default)]
+pub struct PoolStats {
+    // workers running right now, busy or not, and the most there ever were
+    workers: AtomicUsize,
+    busy_workers: AtomicUsize,
+    peak_workers: AtomicUsize,
+    // how many were ever started and how many stopped after going idle
+    spawned: AtomicUsize,
+    retired: AtomicUsize,
+    // jobs waiting for a worker right now
+    queued: AtomicUsize,
+    // the most that were ever waiting at once
+    peak_queued: AtomicUsize,
+    // how many try_reserve turned away
+    rejected: AtomicUsize,
+    // jobs picked up by a worker and the total and longest they waited
+    started: AtomicUsize,
+    total_wait_micros: AtomicU64,
+    longest_wait_micros: AtomicU64,
+    // jobs that ran to the end (or panicked) and how long they took
+    finished: AtomicUsize,
+    panicked: AtomicUsize,
+    total_run_micros: AtomicU64,
+}
+
+impl PoolStats {
+    fn add_queued(&self) {
+        let queued = self.queued.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak_queued.fetch_max(queued, Ordering::Relaxed);
+    }
+
+    fn worker_started(&self) {
+        let workers = self.workers.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak_workers.fetch_max(workers, Ordering::Relaxed);
+        self.spawned.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // takes a worker off the count only if that leaves at least min
+    fn try_retire(&self, min_workers: usize) -> bool {
+        let retired = self.workers.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |workers| {
+            match workers > min_workers {
+                true => Some(workers - 1),
+                false => None,
+            }
+        });
+        if retired.is_ok() {
+            self.retired.fetch_add(1, Ordering::Relaxed);
+        }
+        retired.is_ok()
+    }
+
+    fn job_started(&self, waited: Duration) {
+        self.busy_workers.fetch_add(1, Ordering::SeqCst);
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        let waited = as_micros(waited);
+        self.started.fetch_add(1, Ordering::Relaxed);
+        self.total_wait_micros.fetch_add(waited, Ordering::Relaxed);
+        self.longest_wait_micros.fetch_max(waited, Ordering::Relaxed);
+    }
+
+    fn job_finished(&self, ran: Duration, panicked: bool) {
+        self.busy_workers.fetch_sub(1, Ordering::SeqCst);
+        self.finished.fetch_add(1, Ordering::Relaxed);
+        self.total_run_micros.fetch_add(as_micros(ran), Ordering::Relaxed);
+        if panicked {
+            self.panicked.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn get_workers(&self) -> usize {
+        self.workers.load(Ordering::SeqCst)
+    }
+
+    pub fn get_busy_workers(&self) -> usize {
+        self.busy_workers.load(Ordering::SeqCst)
+    }
+
+    pub fn get_idle_workers(&self) -> usize {
+        self.get_workers().saturating_sub(self.get_busy_workers())
+    }
+
+    pub fn get_peak_workers(&self) -> usize {
+        self.peak_workers.load(Ordering::Relaxed)
+    }
+
+    pub fn get_spawned(&self) -> usize {
+        self.spawned.load(Ordering::Relaxed)
+    }
+
+    pub fn get_retired(&self) -> usize {
+        self.retired.load(Ordering::Relaxed)
+    }
+
+    pub fn get_queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    pub fn get_peak_queued(&self) -> usize {
+        self.peak_queued.load(Ordering::Relaxed)
+    }
+
+    pub fn get_rejected(&self) -> usize {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    pub fn get_started(&self) -> usize {
+        self.started.load(Ordering::Relaxed)
+    }
+
+    pub fn get_finished(&self) -> usize {
+        self.finished.load(Ordering::Relaxed)
+    }
+
+    pub fn get_panicked(&self) -> usize {
+        self.panicked.load(Ordering::Relaxed)
+    }
+
+    // how long jobs sit in the queue before a worker gets to them on average
+    pub fn get_average_wait(&self) -> Duration {
+        average(self.total_wait_micros.load(Ordering::Relaxed), self.get_started())
+    }
+
+    pub fn get_longest_wait(&self) -> Duration {
+        Duration::from_micros(self.longest_wait_micros.load(Ordering::Relaxed))
+    }
+
+    // how long a worker spends on each job, for a connection thats every
+    // request it sent plus any time spent waiting on keep-alive
+    pub fn get_average_run(&self) -> Duration {
+        average(self.total_run_micros.load(Ordering::Relaxed), self.get_finished())
+    }
+}
+
+impl std::fmt::Display for PoolStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "workers: {} ({} busy, peak {}, {} started, {} retired), ",
+            self.get_workers(),
+            self.get_busy_workers(),
+            self.get_peak_workers(),
+            self.get_spawned(),
+            self.get_retired(),
+        )?;
+        write!(
+            f,
+            "queued: {}, peak: {}, rejected: {}, average wait: {:?}, longest wait: {:?}, ",
+            self.get_queued(),
+            self.get_peak_queued(),
+            self.get_rejected(),
+            self.get_average_wait(),
+            self.get_longest_wait(),
+        )?;
+        write!(
+            f,
+            "jobs: {} finished, {} panicked, average run: {:?}",
+            self.get_finished(),
+            self.get_panicked(),
+            self.get_average_run(),
+        )
+    }
+}
+
+fn as_micros(duration: Duration) -> u64 {
+    duration.as_micros().min(u64::MAX as u128) as u64
+}
+
+fn average(total_micros: u64, count: usize) -> Duration {
+    match count {
+        0 => Duration::ZERO,
+        count => Duration::from_micros(total_micros / count as u64),
     }
 }
 
-#[allow(dead_code)]
 struct Worker {
     id: usize,
     thread: JoinHandle<()>,
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            let job = receiver.lock().unwrap().recv().unwrap();
+    fn new(id: usize, shared: Arc<Shared>) -> Result<Worker, std::io::Error> {
+        shared.stats.worker_started();
+        let spawned = thread::Builder::new()
+            .name(format!("worker-{}", id))
+            .spawn({
+                let shared = Arc::clone(&shared);
+                move || Worker::run(shared)
+            });
+
+        match spawned {
+            Ok(thread) => Ok(Worker {
+                id,
+                thread,
+            }),
+            Err(e) => {
+                // it never got to run so it was never really there
+                shared.stats.workers.fetch_sub(1, Ordering::SeqCst);
+                Err(e)
+            },
+        }
+    }
+
+    fn run(shared: Arc<Shared>) {
+        // takes the worker off the count however the thread ends, unless
+        // try_retire already did
+        let mut guard = WorkerGuard {
+            stats: &shared.stats,
+            counted: true,
+        };
+
+        loop {
+            let message = shared.next_job();
+
+            match message {
+                Ok((queued_at, job)) => {
+                    shared.stats.job_started(queued_at.elapsed());
+                    let started = Instant::now();
+
+                    // a panicking job would otherwise take the worker down
+                    // with it, the panic message has already been printed
+                    let panicked = panic::catch_unwind(AssertUnwindSafe(job)).is_err();
+                    shared.stats.job_finished(started.elapsed(), panicked);
+                },
+                Err(RecvTimeoutError::Timeout) => {
+                    if shared.stats.try_retire(shared.min_workers) {
+                        guard.counted = false;
+                        println!("{} retiring after being idle for {:?}", thread_name(), shared.idle_timeout);
+                        return;
+                    }
+                },
+                // the pool was shut down and theres nothing left to do
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+
+    fn join(self) {
+        if self.thread.join().is_err() {
+            println!("worker-{} panicked", self.id);
+        }
+    }
+}
+
+struct WorkerGuard<'a> {
+    stats: &'a PoolStats,
+    counted: bool,
+}
+
+impl Drop for WorkerGuard<'_> {
+    fn drop(&mut self) {
+        if self.counted {
+            self.stats.workers.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
 
-            println!("Worker {id} got a job; executing.");
+fn thread_name() -> String {
+    thread::current().name().unwrap_or("worker").to_owned()
+}
+
+impl Shared {
+    // a reserved slot already made room so it never waits, even if execute
+    // has filled the queue up since
+    fn push(&self, job: QueuedJob, wait_for_room: bool) {
+        let mut queue = self.lock_queue();
+        while wait_for_room && queue.jobs.len() >= self.max_queue {
+            queue = self.room_made.wait(queue).unwrap_or_else(PoisonError::into_inner);
+        }
+        queue.jobs.push_back(job);
+        self.job_added.notify_one();
+    }
+
+    // the next job, or an error once the worker has been idle for
+    // idle_timeout or the pool is shut down and theres nothing left
+    fn next_job(&self) -> Result<QueuedJob, RecvTimeoutError> {
+        let deadline = Instant::now() + self.idle_timeout;
+        let mut queue = self.lock_queue();
+        loop {
+            if let Some(job) = queue.jobs.pop_front() {
+                self.room_made.notify_one();
+                return Ok(job);
+            }
+            if queue.closed {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            // wakes up early sometimes so this just goes around again
+            queue = self.job_added.wait_timeout(queue, remaining)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+
+    fn close(&self) {
+        self.lock_queue().closed = true;
+        self.job_added.notify_all();
+    }
 
-            job();
+    // nothing panics while holding this but if something ever did every
+    // other worker would panic on the next lock, the queue itself is still fine
+    fn lock_queue(&self) -> MutexGuard<'_, JobQueue> {
+        self.queue.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Condvar;
+
+    // jobs that dont finish until the gate is opened, for keeping workers busy
+    #[derive(Clone, Default)]
+    struct Gate {
+        open: Arc<(Mutex<bool>, Condvar)>,
+    }
+
+    impl Gate {
+        fn job(&self) -> impl FnOnce() + Send + 'static {
+            let gate = self.clone();
+            move || {
+                let (open, opened) = &*gate.open;
+                let mut open = open.lock().unwrap();
+                while !*open {
+                    open = opened.wait(open).unwrap();
+                }
+            }
+        }
+
+        fn open(&self) {
+            let (open, opened) = &*self.open;
+            *open.lock().unwrap() = true;
+            opened.notify_all();
+        }
+    }
+
+    // true once check is, false if it still isnt after timeout
+    fn wait_for(timeout: Duration, check: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + timeout;
+        while !check() {
+            if Instant::now() > deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        true
+    }
+
+    fn counting_job(count: &Arc<AtomicUsize>) -> impl FnOnce() + Send + 'static {
+        let count = Arc::clone(count);
+        move || {
+            thread::sleep(Duration::from_millis(5));
+            count.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn shutdown_runs_everything_already_queued() {
+        let pool = ThreadPool::new(2);
+        let count = Arc::new(AtomicUsize::new(0));
+        (0..10).for_each(|_| pool.execute(counting_job(&count)));
+
+        assert_eq!(pool.shutdown(Duration::from_secs(5)), 0);
+        assert_eq!(count.load(Ordering::SeqCst), 10);
+    }
+
+    #[test]
+    fn shutdown_gives_up_on_jobs_that_take_too_long() {
+        let pool = ThreadPool::new(2);
+        let gate = Gate::default();
+        pool.execute(gate.job());
+        let stats = pool.stats();
+        assert!(wait_for(Duration::from_secs(5), || stats.get_busy_workers() == 1));
+
+        let started = Instant::now();
+        assert_eq!(pool.shutdown(Duration::from_millis(100)), 1);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        gate.open();
+    }
+
+    #[test]
+    fn dropping_the_pool_waits_for_its_jobs() {
+        let count = Arc::new(AtomicUsize::new(0));
+        {
+            let pool = ThreadPool::new(1);
+            (0..3).for_each(|_| pool.execute(counting_job(&count)));
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn a_panicking_job_doesnt_take_its_worker_down() {
+        let pool = ThreadPool::new(1);
+        let stats = pool.stats();
+        let count = Arc::new(AtomicUsize::new(0));
+        pool.execute(|| panic!("job went wrong"));
+        pool.execute(counting_job(&count));
+
+        assert!(wait_for(Duration::from_secs(5), || count.load(Ordering::SeqCst) == 1));
+        assert_eq!(stats.get_panicked(), 1);
+        assert_eq!(stats.get_finished(), 2);
+        assert_eq!(stats.get_workers(), 1);
+        assert_eq!(stats.get_spawned(), 1);
+    }
+
+    #[test]
+    fn a_full_queue_turns_reservations_away() {
+        let pool = ThreadPool::with_queue(1, 2);
+        let stats = pool.stats();
+        let gate = Gate::default();
+        pool.execute(gate.job());
+        assert!(wait_for(Duration::from_secs(5), || stats.get_busy_workers() == 1));
+
+        let first = pool.try_reserve().unwrap();
+        assert!(!pool.is_full());
+        let second = pool.try_reserve().unwrap();
+        assert!(pool.is_full());
+        assert!(pool.try_reserve().is_none());
+        assert_eq!(stats.get_rejected(), 1);
+        assert_eq!(stats.get_peak_queued(), 2);
+
+        // dropping a slot gives its place back without running anything
+        drop(first);
+        assert!(!pool.is_full());
+        assert_eq!(stats.get_queued(), 1);
+
+        let count = Arc::new(AtomicUsize::new(0));
+        second.execute(counting_job(&count));
+        gate.open();
+        assert!(wait_for(Duration::from_secs(5), || count.load(Ordering::SeqCst) == 1));
+        assert_eq!(stats.get_queued(), 0);
+        assert_eq!(stats.get_started(), 2);
+    }
+
+    #[test]
+    fn execute_waits_for_room_in_the_queue() {
+        let pool = Arc::new(ThreadPool::with_queue(1, 1));
+        let stats = pool.stats();
+        let gate = Gate::default();
+        pool.execute(gate.job());
+        assert!(wait_for(Duration::from_secs(5), || stats.get_busy_workers() == 1));
+        pool.execute(gate.job());
+        assert!(pool.is_full());
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let blocked = thread::spawn({
+            let pool = Arc::clone(&pool);
+            let job = counting_job(&count);
+            move || pool.execute(job)
         });
+        thread::sleep(Duration::from_millis(100));
+        assert!(!blocked.is_finished());
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+
+        gate.open();
+        blocked.join().unwrap();
+        assert!(wait_for(Duration::from_secs(5), || count.load(Ordering::SeqCst) == 1));
+        assert_eq!(stats.get_rejected(), 0);
+    }
+
+    #[test]
+    fn waiting_time_is_counted() {
+        let pool = ThreadPool::with_queue(1, 4);
+        let stats = pool.stats();
+        let gate = Gate::default();
+        pool.execute(gate.job());
+        pool.execute(|| {});
+        thread::sleep(Duration::from_millis(50));
+        gate.open();
 
-        Worker {
-            id, 
-            thread,
+        assert!(wait_for(Duration::from_secs(5), || stats.get_finished() == 2));
+        assert!(stats.get_longest_wait() >= Duration::from_millis(50));
+        assert!(stats.get_average_wait() > Duration::ZERO);
+    }
+
+    #[test]
+    fn idle_workers_all_retire_together() {
+        let idle_timeout = Duration::from_millis(300);
+        let pool = ThreadPool::with_config(PoolConfig {
+            min_workers: 1,
+            max_workers: 4,
+            max_queue: 16,
+            idle_timeout,
+        });
+        let stats = pool.stats();
+        let gate = Gate::default();
+        (0..4).for_each(|_| pool.execute(gate.job()));
+        assert!(wait_for(Duration::from_secs(5), || stats.get_busy_workers() == 4));
+        gate.open();
+        assert!(wait_for(Duration::from_secs(5), || stats.get_busy_workers() == 0));
+
+        // one after another would take three timeouts
+        let started = Instant::now();
+        assert!(wait_for(Duration::from_secs(5), || stats.get_workers() == 1));
+        assert!(started.elapsed() < idle_timeout * 2);
+        assert_eq!(stats.get_retired(), 3);
+    }
+
+    #[test]
+    fn the_pool_grows_up_to_max_workers_when_backed_up() {
+        let pool = ThreadPool::with_config(PoolConfig {
+            min_workers: 1,
+            max_workers: 3,
+            max_queue: 16,
+            idle_timeout: Duration::from_secs(60),
+        });
+        let stats = pool.stats();
+        assert_eq!(stats.get_workers(), 1);
+
+        let gate = Gate::default();
+        let names = Arc::new(Mutex::new(Vec::new()));
+        for _ in 0..6 {
+            let names = Arc::clone(&names);
+            let job = gate.job();
+            pool.execute(move || {
+                names.lock().unwrap().push(thread_name());
+                job();
+            });
         }
+        assert!(wait_for(Duration::from_secs(5), || stats.get_busy_workers() == 3));
+        assert_eq!(stats.get_workers(), 3);
+        assert_eq!(stats.get_peak_workers(), 3);
+        assert_eq!(stats.get_queued(), 3);
+
+        gate.open();
+        assert!(wait_for(Duration::from_secs(5), || stats.get_finished() == 6));
+        assert_eq!(stats.get_spawned(), 3);
+        assert_eq!(stats.get_retired(), 0);
+
+        let mut names = names.lock().unwrap().clone();
+        names.sort();
+        names.dedup();
+        assert_eq!(names, ["worker-0", "worker-1", "worker-2"]);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,292 @@
+use std::io::{self, BufReader, Cursor, ErrorKind, Read};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+// how long a connection gets for each part of a request. read and write are
+// per call to the socket, header and body are for the whole thing so a client
+// sending one byte every few seconds (slowloris) still gets cut off
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionTimeouts {
+    // waiting for the first byte of a request, on a new or keep-alive connection
+    pub idle: Duration,
+    pub read: Duration,
+    pub write: Duration,
+    // from the first byte of the request line to the empty line after the header
+    pub header: Duration,
+    // from the end of the header to the end of the body
+    pub body: Duration,
+}
+
+impl Default for ConnectionTimeouts {
+    fn default() -> Self {
+        Self {
+            idle: Duration::from_secs(5),
+            read: Duration::from_secs(10),
+            write: Duration::from_secs(10),
+            header: Duration::from_secs(10),
+            body: Duration::from_secs(30),
+        }
+    }
+}
+
+// how many connections got dropped for being too slow at each step, kept
+// for the whole time the server is running
+static IDLE_DROPS: AtomicUsize = AtomicUsize::new(0);
+static HEADER_DROPS: AtomicUsize = AtomicUsize::new(0);
+static BODY_DROPS: AtomicUsize = AtomicUsize::new(0);
+static WRITE_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy)]
+pub enum SlowStep {
+    Idle,
+    Header,
+    Body,
+    Write,
+}
+
+pub fn record_slow_drop(step: SlowStep) {
+    let counter = match step {
+        SlowStep::Idle => &IDLE_DROPS,
+        SlowStep::Header => &HEADER_DROPS,
+        SlowStep::Body => &BODY_DROPS,
+        SlowStep::Write => &WRITE_DROPS,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SlowDrops {
+    pub idle: usize,
+    pub header: usize,
+    pub body: usize,
+    pub write: usize,
+}
+
+pub fn get_slow_drops() -> SlowDrops {
+    SlowDrops {
+        idle: IDLE_DROPS.load(Ordering::Relaxed),
+        header: HEADER_DROPS.load(Ordering::Relaxed),
+        body: BODY_DROPS.load(Ordering::Relaxed),
+        write: WRITE_DROPS.load(Ordering::Relaxed),
+    }
+}
+
+impl std::fmt::Display for SlowDrops {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "idle: {}, header: {}, body: {}, write: {}",
+            self.idle, self.header, self.body, self.write
+        )
+    }
+}
+
+pub fn is_timeout(error: &io::Error) -> bool {
+    matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Phase {
+    Idle,
+    Header(Instant),
+    Body(Instant),
+}
+
+// the socket with the deadline for whatever part of the request is being
+// read, every read gets whichever is shorter out of the read timeout and
+// the time left before the deadline
+#[derive(Debug)]
+pub struct TimedStream {
+    stream: TcpStream,
+    timeouts: ConnectionTimeouts,
+    phase: Phase,
+    // how many requests have been started on this connection
+    requests: usize,
+}
+
+impl TimedStream {
+    pub fn new(stream: TcpStream, timeouts: ConnectionTimeouts) -> Result<Self, io::Error> {
+        stream.set_write_timeout(Some(timeouts.write))?;
+        Ok(Self {
+            stream,
+            timeouts,
+            phase: Phase::Idle,
+            requests: 0,
+        })
+    }
+
+    // called before each request, the header deadline starts with the first
+    // byte. pipelined is for when part of the request is already buffered
+    pub fn wait_for_request(&mut self, pipelined: bool) {
+        self.requests += 1;
+        self.phase = match pipelined {
+            true => Phase::Header(Instant::now() + self.timeouts.header),
+            false => Phase::Idle,
+        };
+    }
+
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    // responses are written straight to the socket
+    pub fn get_mut(&mut self) -> &mut TcpStream {
+        &mut self.stream
+    }
+
+    // a keep-alive connection going quiet is normal, its only slow if it
+    // never sent anything at all or stopped part way through a request
+    fn record_timeout(&self) {
+        match self.phase {
+            Phase::Idle if self.requests <= 1 => record_slow_drop(SlowStep::Idle),
+            Phase::Idle => {},
+            Phase::Header(_) => record_slow_drop(SlowStep::Header),
+            Phase::Body(_) => record_slow_drop(SlowStep::Body),
+        }
+    }
+}
+
+impl Read for TimedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = match self.phase {
+            Phase::Idle => self.timeouts.idle,
+            Phase::Header(deadline) | Phase::Body(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                // a zero timeout means no timeout at all to set_read_timeout
+                if remaining.is_zero() {
+                    self.record_timeout();
+                    return Err(io::Error::new(ErrorKind::TimedOut, "deadline for the request passed"));
+                }
+                remaining.min(self.timeouts.read)
+            },
+        };
+        self.stream.set_read_timeout(Some(timeout))?;
+
+        match self.stream.read(buf) {
+            Ok(read) => {
+                if let (Phase::Idle, true) = (self.phase, read > 0) {
+                    self.phase = Phase::Header(Instant::now() + self.timeouts.header);
+                }
+                Ok(read)
+            },
+            Err(e) => {
+                if is_timeout(&e) {
+                    self.record_timeout();
+                }
+                Err(e)
+            },
+        }
+    }
+}
+
+// lets Request::new say when its done with the header so the body gets its
+// own deadline, readers without deadlines (like a byte slice) ignore it
+pub trait HeaderDeadline {
+    fn header_received(&mut self) {}
+}
+
+impl HeaderDeadline for TimedStream {
+    fn header_received(&mut self) {
+        self.phase = Phase::Body(Instant::now() + self.timeouts.body);
+    }
+}
+
+impl<R: Read + HeaderDeadline> HeaderDeadline for BufReader<R> {
+    fn header_received(&mut self) {
+        self.get_mut().header_received();
+    }
+}
+
+impl HeaderDeadline for &[u8] {}
+
+impl<T> HeaderDeadline for Cursor<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+    use crate::types::{HTTPError, Request, RequestLimits, StatusCode};
+
+    const SHORT: ConnectionTimeouts = ConnectionTimeouts {
+        idle: Duration::from_millis(100),
+        read: Duration::from_millis(100),
+        write: Duration::from_millis(100),
+        header: Duration::from_millis(300),
+        body: Duration::from_millis(300),
+    };
+
+    // the client end and the server end wrapped up the way handle_connection does
+    fn connect() -> (TcpStream, BufReader<TimedStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let mut server = BufReader::new(TimedStream::new(server, SHORT).unwrap());
+        server.get_mut().wait_for_request(false);
+        (client, server)
+    }
+
+    fn read_request(server: &mut BufReader<TimedStream>) -> Result<Request, HTTPError> {
+        Request::new(server, &RequestLimits::default())
+    }
+
+    #[test]
+    fn a_stalled_header_is_a_408() {
+        let (mut client, mut server) = connect();
+        client.write_all(b"GET / HTTP/1.1\r\nX-Forw").unwrap();
+
+        let error = read_request(&mut server).unwrap_err();
+        assert!(matches!(error, HTTPError::RequestTimeout));
+        assert_eq!(error.get_status_code(), StatusCode::RequestTimeout);
+    }
+
+    #[test]
+    fn dripping_bytes_doesnt_get_past_the_header_deadline() {
+        let (mut client, mut server) = connect();
+        let before = get_slow_drops().header;
+        // every byte comes well inside the read timeout but the whole header never does
+        let dripper = thread::spawn(move || {
+            for byte in b"GET / HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\n" {
+                if client.write_all(&[*byte]).is_err() {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(30));
+            }
+        });
+
+        let started = Instant::now();
+        assert!(matches!(read_request(&mut server), Err(HTTPError::RequestTimeout)));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(get_slow_drops().header > before);
+        drop(server);
+        dripper.join().unwrap();
+    }
+
+    #[test]
+    fn a_stalled_body_is_a_408() {
+        let (mut client, mut server) = connect();
+        let before = get_slow_drops().body;
+        client.write_all(b"POST / HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\nContent-Length: 10\r\n\r\nabc").unwrap();
+
+        assert!(matches!(read_request(&mut server), Err(HTTPError::RequestTimeout)));
+        assert!(get_slow_drops().body > before);
+    }
+
+    #[test]
+    fn an_idle_connection_is_just_closed() {
+        let (_client, mut server) = connect();
+        let before = get_slow_drops().idle;
+        assert!(matches!(read_request(&mut server), Err(HTTPError::ConnectionClosed)));
+        // it never sent anything at all so it counts as slow
+        assert!(get_slow_drops().idle > before);
+    }
+
+    #[test]
+    fn requests_in_time_are_read() {
+        let (mut client, mut server) = connect();
+        client.write_all(b"GET /ok HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\n\r\n").unwrap();
+        assert_eq!(read_request(&mut server).unwrap().get_path(), "/ok");
+    }
+}
@@ -0,0 +1,248 @@
+// turns '%2e' and friends back into the bytes they stand for, None if an
+// escape is broken ('%4', '%zz') or the result isnt valid utf-8
+pub fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let high = hex_value(*bytes.get(i + 1)?)?;
+                let low = hex_value(*bytes.get(i + 2)?)?;
+                decoded.push(high << 4 | low);
+                i += 3;
+            },
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            },
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+fn hex_value(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+// decodes the path from the request line and squashes it down to the one
+// canonical form, '/a/./b//c/../d' is '/a/b/d'. Anything that would climb
+// above the root is refused instead of being clamped so the client finds
+// out it did something wrong. Decoding happens first so '%2e%2e' and '%2f'
+// get treated just like '..' and '/', and backslashes count as slashes so
+// they cant sneak a '..\' past on windows
+pub fn normalize_path(raw: &str) -> Option<String> {
+    // checked before decoding as an escaped '%2f' isnt the start of a path
+    if !raw.starts_with('/') {
+        return None;
+    }
+    let decoded = percent_decode(raw)?;
+    if decoded.contains('\0') {
+        return None;
+    }
+
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in decoded.split(['/', '\\']) {
+        match segment {
+            "" | "." => {},
+            ".." => {
+                segments.pop()?;
+            },
+            segment => segments.push(segment),
+        }
+    }
+
+    let mut path = format!("/{}", segments.join("/"));
+    // the trailing slash is kept as it means the client wanted the directory
+    let trailing = matches!(decoded.rsplit(['/', '\\']).next(), Some("" | "." | ".."));
+    if trailing && !segments.is_empty() {
+        path.push('/');
+    }
+
+    Some(path)
+}
+
+// the '+' means a space in query strings and form bodies, a real plus has
+// to be sent as '%2B'
+pub fn form_decode(input: &str) -> Option<String> {
+    percent_decode(&input.replace('+', " "))
+}
+
+// an application/x-www-form-urlencoded list of pairs, which is what query
+// strings are too: 'q=C%2B%2B+%26+Rust&tag=a&tag=b&debug'
+// keys can show up more than once and dont need a value ('debug' is "")
+#[derive(Debug, Clone, Default)]
+pub struct FormData {
+    pairs: Vec<(String, String)>,
+}
+
+impl FormData {
+    pub fn new() -> Self {
+        Self {
+            pairs: Vec::new(),
+        }
+    }
+
+    // None if any of the escapes are broken
+    pub fn parse(input: &str) -> Option<Self> {
+        let mut form = Self::new();
+        for pair in input.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            form.pairs.push((form_decode(key)?, form_decode(value)?));
+        }
+        Some(form)
+    }
+
+    // the first value sent for the key
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.pairs.iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // every value sent for the key in the order they came in
+    pub fn get_all(&self, key: &str) -> Vec<&str> {
+        self.pairs.iter()
+            .filter(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.pairs.iter().any(|(k, _)| k == key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.pairs.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_paths_are_left_alone() {
+        assert_eq!(normalize_path("/").as_deref(), Some("/"));
+        assert_eq!(normalize_path("/blog/post.html").as_deref(), Some("/blog/post.html"));
+        assert_eq!(normalize_path("/examples/").as_deref(), Some("/examples/"));
+        assert_eq!(normalize_path("/caf%C3%A9%20menu").as_deref(), Some("/café menu"));
+    }
+
+    #[test]
+    fn dots_and_double_slashes_are_squashed() {
+        assert_eq!(normalize_path("/a/./b//c/../d").as_deref(), Some("/a/b/d"));
+        assert_eq!(normalize_path("//a///b").as_deref(), Some("/a/b"));
+        assert_eq!(normalize_path("/a/b/..").as_deref(), Some("/a/"));
+        assert_eq!(normalize_path("/a/b/.").as_deref(), Some("/a/b/"));
+        assert_eq!(normalize_path("/a/..").as_deref(), Some("/"));
+    }
+
+    #[test]
+    fn escaped_dots_and_slashes_count_as_the_real_thing() {
+        assert_eq!(normalize_path("/a/%2e%2e/b").as_deref(), Some("/b"));
+        assert_eq!(normalize_path("/a/%2E%2E/b").as_deref(), Some("/b"));
+        assert_eq!(normalize_path("/a%2fb").as_deref(), Some("/a/b"));
+        assert_eq!(normalize_path("/a/b%2f..%2f..%2fc").as_deref(), Some("/c"));
+    }
+
+    #[test]
+    fn backslashes_count_as_slashes() {
+        assert_eq!(normalize_path("/a\\b").as_deref(), Some("/a/b"));
+        assert_eq!(normalize_path("/a\\..\\b").as_deref(), Some("/b"));
+        assert_eq!(normalize_path("/a%5c..%5cb").as_deref(), Some("/b"));
+    }
+
+    #[test]
+    fn climbing_above_the_root_is_refused() {
+        for payload in [
+            "/..",
+            "/../",
+            "/a/../..",
+            "/../etc/passwd",
+            "/a/../../etc/passwd",
+            "/%2e%2e/etc/passwd",
+            "/%2e%2e%2fetc%2fpasswd",
+            "/.%2e/etc/passwd",
+            "/a/%2e%2e/%2e%2e/etc/passwd",
+            "/..\\etc\\passwd",
+            "/a\\..\\..\\etc\\passwd",
+            "/%5c..%5c..%5cetc%5cpasswd",
+            "//../etc/passwd",
+        ] {
+            assert_eq!(normalize_path(payload), None, "{}", payload);
+        }
+    }
+
+    #[test]
+    fn nul_bytes_are_refused() {
+        assert_eq!(normalize_path("/index.html%00.png"), None);
+        assert_eq!(normalize_path("/a\0b"), None);
+    }
+
+    #[test]
+    fn broken_escapes_are_refused() {
+        for payload in ["/%", "/a%2", "/%zz", "/%g0", "/%ff", "/%c3"] {
+            assert_eq!(normalize_path(payload), None, "{}", payload);
+        }
+    }
+
+    #[test]
+    fn paths_have_to_start_at_the_root() {
+        assert_eq!(normalize_path("a/b"), None);
+        assert_eq!(normalize_path(""), None);
+        assert_eq!(normalize_path("%2fa"), None);
+    }
+
+    #[test]
+    fn percent_escapes_are_decoded() {
+        assert_eq!(percent_decode("a%20b%2Fc").as_deref(), Some("a b/c"));
+        assert_eq!(percent_decode("%e2%9c%93").as_deref(), Some("\u{2713}"));
+        assert_eq!(percent_decode("a+b").as_deref(), Some("a+b"));
+        // half of a utf-8 character
+        assert_eq!(percent_decode("%e2%9c"), None);
+    }
+
+    #[test]
+    fn forms_are_split_into_pairs() {
+        let form = FormData::parse("q=C%2B%2B+%26+Rust&tag=a&tag=b&debug&=empty&&").unwrap();
+        assert_eq!(form.get("q"), Some("C++ & Rust"));
+        assert_eq!(form.get("tag"), Some("a"));
+        assert_eq!(form.get_all("tag"), vec!["a", "b"]);
+        assert_eq!(form.get("debug"), Some(""));
+        assert!(form.contains("debug"));
+        assert_eq!(form.get(""), Some("empty"));
+        assert_eq!(form.get("missing"), None);
+        assert_eq!(form.len(), 5);
+        assert_eq!(form.iter().next(), Some(("q", "C++ & Rust")));
+    }
+
+    #[test]
+    fn values_can_have_equals_signs() {
+        let form = FormData::parse("token=abc%3D%3D&eq=a=b").unwrap();
+        assert_eq!(form.get("token"), Some("abc=="));
+        assert_eq!(form.get("eq"), Some("a=b"));
+    }
+
+    #[test]
+    fn broken_forms_are_refused() {
+        assert!(FormData::parse("a=%zz").is_none());
+        assert!(FormData::parse("%4=1").is_none());
+        assert!(FormData::parse("").unwrap().is_empty());
+    }
+}